@@ -1,36 +1,134 @@
-use anyhow::anyhow;
 use serde::Serialize;
 use std::collections::HashMap;
 
+use crate::bots::BotFilter;
+use crate::device_id::DeviceIdSource;
+use crate::error::ErrorMode;
 use crate::exports::edgee::components::data_collection::{Dict, Event};
+use crate::headers::HeaderPolicy;
+use crate::internal_traffic::InternalTraffic;
+use crate::mapping::FieldMappings;
+use crate::routing::Routes;
+use crate::settings::Settings;
+use crate::static_properties::StaticProperties;
+use crate::trace::Trace;
 
 #[derive(Serialize, Debug, Default)]
 pub(crate) struct AmplitudePayload {
-    api_key: String,
+    pub(crate) api_key: String,
     #[serde(skip)]
     pub endpoint: String,
     pub(crate) events: Vec<AmplitudeEvent>,
     options: AmplitudeOptions,
+    #[serde(skip)]
+    pub(crate) experiment: Option<ExperimentSettings>,
+    #[serde(skip)]
+    pub(crate) session_replay: bool,
+    #[serde(skip)]
+    pub(crate) autocapture: bool,
+    #[serde(skip)]
+    pub(crate) session_events: Option<SessionEventNames>,
+    #[serde(skip)]
+    pub(crate) user_properties_merge: UserPropertiesMerge,
+    #[serde(skip)]
+    pub(crate) device_id_source: DeviceIdSource,
+    #[serde(skip)]
+    pub(crate) user_id_change: UserIdChange,
+    #[serde(skip)]
+    pub(crate) usermap_endpoint: String,
+    #[serde(skip)]
+    pub(crate) deletion: DeletionSettings,
+    #[serde(skip)]
+    pub(crate) internal_traffic: Option<InternalTraffic>,
+    #[serde(skip)]
+    pub(crate) bot_filter: Option<BotFilter>,
+    #[serde(skip)]
+    pub(crate) sampling: Option<Sampling>,
+    #[serde(skip)]
+    pub(crate) headers: HeaderPolicy,
+    #[serde(skip)]
+    pub(crate) error_mode: ErrorMode,
+    #[serde(skip)]
+    pub(crate) field_mappings: FieldMappings,
+    #[serde(skip)]
+    pub(crate) static_properties: Option<StaticProperties>,
+    #[serde(skip)]
+    pub(crate) trace: Trace,
 }
 
 impl AmplitudePayload {
-    pub(crate) fn new(settings: Dict) -> anyhow::Result<Self> {
-        let cred: HashMap<String, String> = settings
-            .iter()
-            .map(|(key, value)| (key.to_string(), value.to_string()))
-            .collect();
-
-        let api_key = match cred.get("amplitude_api_key") {
-            Some(key) => key,
-            None => return Err(anyhow!("Missing Amplitude API KEY")),
+    pub(crate) fn new(settings: Dict, edgee_event: &Event) -> anyhow::Result<Self> {
+        let settings = Settings::parse(&settings)?;
+
+        let mut api_key = settings.api_key;
+        let mut endpoint = settings.endpoint;
+        let mut usermap_endpoint = settings.usermap_endpoint;
+
+        let experiment = settings.experiment_exposures.then_some(ExperimentSettings {
+            flag_key_property: settings.experiment_flag_key_property,
+            variant_property: settings.experiment_variant_property,
+            experiment_key_property: settings.experiment_key_property,
+        });
+
+        let device_id_source = DeviceIdSource::parse(&settings.device_id_source)?;
+
+        let mut deletion = DeletionSettings {
+            secret_key: settings.secret_key,
+            endpoint: settings.deletion_endpoint,
+            requester: settings.deletion_requester,
+        };
+
+        let internal_traffic = InternalTraffic::parse(
+            &settings.internal_ips,
+            &settings.internal_user_ids,
+            &settings.internal_device_ids,
+            settings.internal_traffic,
+        )?;
+
+        let bot_filter = settings
+            .bot_filtering
+            .map(|policy| BotFilter::new(policy, &settings.bot_patterns));
+
+        let sampling = settings
+            .sample_rate
+            .filter(|rate| *rate < 1.0)
+            .map(|rate| Sampling {
+                rate,
+                by_user_id: settings.sample_by_user_id,
+                rate_property: settings.sample_rate_property,
+            });
+
+        let headers = HeaderPolicy::parse(
+            settings.forward_client_headers,
+            &settings.client_headers_allowlist,
+            &settings.headers,
+            &settings.secret_headers,
+        )?;
+
+        let mut trace = Trace::new(settings.debug);
+        for key in &settings.unknown {
+            trace.unknown_setting(key);
         }
-        .to_string();
 
-        let endpoint = cred
-            .get("amplitude_endpoint")
-            .cloned()
-            .filter(|s| !s.is_empty())
-            .unwrap_or_else(|| crate::DEFAULT_ENDPOINT.to_owned());
+        // send the event to the project of the first matching route
+        let routes = Routes::new(settings.routes)?;
+        if let Some(route) = routes.find(edgee_event) {
+            api_key = route.api_key.clone();
+            if let Some(route_endpoint) = route.endpoint.as_ref().filter(|s| !s.is_empty()) {
+                endpoint = route_endpoint.clone();
+            }
+            if route.secret_key.is_some() {
+                deletion.secret_key = route.secret_key.clone();
+            }
+            if let Some(route_endpoint) = route.usermap_endpoint.as_ref().filter(|s| !s.is_empty())
+            {
+                usermap_endpoint = route_endpoint.clone();
+            }
+            if let Some(route_endpoint) = route.deletion_endpoint.as_ref().filter(|s| !s.is_empty())
+            {
+                deletion.endpoint = route_endpoint.clone();
+            }
+        }
 
         Ok(Self {
             api_key,
@@ -39,17 +137,185 @@ impl AmplitudePayload {
                 min_id_length: Option::from(1),
             },
             events: vec![],
+            experiment,
+            session_replay: settings.session_replay,
+            autocapture: settings.autocapture_preset,
+            session_events: settings.session_events,
+            user_properties_merge: settings.user_properties_merge,
+            device_id_source,
+            user_id_change: settings.user_id_change,
+            usermap_endpoint,
+            deletion,
+            internal_traffic,
+            bot_filter,
+            sampling,
+            headers,
+            error_mode: settings.error_mode,
+            field_mappings: FieldMappings::new(settings.field_mappings)?.with_client_properties(
+                &settings.client_properties,
+                settings.client_properties_destination,
+            )?,
+            static_properties: StaticProperties::new(
+                settings.static_event_properties,
+                settings.static_user_properties,
+                settings.static_properties_precedence,
+            ),
+            trace,
+        })
+    }
+}
+
+/// How the traits of a user event are combined with the user properties
+/// built from the event context (attribution and context traits).
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) enum UserPropertiesMerge {
+    /// Only the traits of the user event are sent.
+    Explicit,
+    /// Context properties win over the traits of the user event.
+    Context,
+    /// Both are sent, the traits of the user event win.
+    #[default]
+    Both,
+}
+
+/// How user events changing the user id of the context are sent to Amplitude.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) enum UserIdChange {
+    /// An `identify` event is sent to the HTTP V2 API.
+    #[default]
+    Identify,
+    /// The previous user id is mapped to the new one with the User Mapping
+    /// API, unless the event carries traits, which only `identify` can send.
+    Usermap,
+}
+
+/// Settings of the User Privacy API, used for deletion requests.
+#[derive(Debug, Default)]
+pub(crate) struct DeletionSettings {
+    pub(crate) secret_key: Option<String>,
+    pub(crate) endpoint: String,
+    pub(crate) requester: Option<String>,
+}
+
+/// Names of the synthetic events sent at session boundaries.
+#[derive(Debug)]
+pub(crate) struct SessionEventNames {
+    pub(crate) start: String,
+    pub(crate) end: String,
+}
+
+/// Names of the track properties holding an Amplitude Experiment assignment.
+#[derive(Debug)]
+pub(crate) struct ExperimentSettings {
+    flag_key_property: String,
+    variant_property: String,
+    experiment_key_property: String,
+}
+
+/// A flag assignment read from the properties of a track event.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Exposure {
+    pub(crate) flag_key: String,
+    pub(crate) variant: String,
+    pub(crate) experiment_key: Option<String>,
+}
+
+impl ExperimentSettings {
+    /// Returns the exposure described by these properties, if they carry both
+    /// a flag key and a variant.
+    pub(crate) fn exposure(&self, properties: &Dict) -> Option<Exposure> {
+        let get = |name: &str| {
+            properties
+                .iter()
+                .find(|(key, value)| key == name && !value.is_empty())
+                .map(|(_, value)| value.clone())
+        };
+
+        Some(Exposure {
+            flag_key: get(&self.flag_key_property)?,
+            variant: get(&self.variant_property)?,
+            experiment_key: get(&self.experiment_key_property),
         })
     }
+
+    /// Whether the property is one of the experiment properties.
+    pub(crate) fn is_experiment_property(&self, key: &str) -> bool {
+        key == self.flag_key_property
+            || key == self.variant_property
+            || key == self.experiment_key_property
+    }
+}
+
+/// User-level sampling: a user is kept or dropped for all of their events.
+#[derive(Debug)]
+pub(crate) struct Sampling {
+    pub(crate) rate: f64,
+    /// Whether users are sampled by user_id rather than device_id.
+    pub(crate) by_user_id: bool,
+    /// Whether events carry the `sample_rate` event property.
+    pub(crate) rate_property: bool,
+}
+
+impl Sampling {
+    /// Whether the events of this id are sent, a stable decision for a given
+    /// id and rate.
+    pub(crate) fn keep(&self, id: &str) -> bool {
+        let hash = fmix64(crate::device_id::hash(id.as_bytes()));
+        // the top 53 bits, exactly representable as a f64 in [0, 1)
+        let position = (hash >> 11) as f64 / (1u64 << 53) as f64;
+        position < self.rate
+    }
 }
 
+/// MurmurHash3 finalizer. FNV-1a barely mixes the last bytes into the high
+/// bits, so ids differing only at the end, such as sequential ids, would be
+/// sampled at a biased rate.
+fn fmix64(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+/// Top-level fields of the event that field mappings can write.
+pub(crate) const EVENT_FIELDS: &[&str] = &[
+    "app_version",
+    "platform",
+    "os_name",
+    "os_version",
+    "device_brand",
+    "device_manufacturer",
+    "device_model",
+    "carrier",
+    "country",
+    "region",
+    "city",
+    "dma",
+    "language",
+    "price",
+    "quantity",
+    "revenue",
+    "product_id",
+    "revenue_type",
+    "location_lat",
+    "location_lng",
+    "ip",
+    "idfa",
+    "idfv",
+    "adid",
+    "android_id",
+    "event_id",
+    "user_agent",
+];
+
 #[derive(Serialize, Debug, Default)]
 pub(crate) struct AmplitudeEvent {
     #[serde(rename = "user_id", skip_serializing_if = "Option::is_none")]
     pub(crate) user_id: Option<String>,
     #[serde(rename = "device_id", skip_serializing_if = "Option::is_none")]
-    device_id: Option<String>,
-    event_type: String,
+    pub(crate) device_id: Option<String>,
+    pub(crate) event_type: String,
     #[serde(rename = "event_properties", skip_serializing_if = "Option::is_none")]
     pub(crate) event_properties: Option<serde_json::Value>,
     #[serde(rename = "user_properties", skip_serializing_if = "Option::is_none")]
@@ -119,7 +385,7 @@ pub(crate) struct AmplitudeEvent {
     #[serde(skip_serializing_if = "Option::is_none")]
     session_id: Option<u64>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    insert_id: Option<String>,
+    pub(crate) insert_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     plan: Option<PlanProperties>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -133,31 +399,11 @@ impl AmplitudeEvent {
         event_type: &str,
         edgee_event: &Event,
         session_id: u64,
-    ) -> anyhow::Result<Self> {
+        device_id: Option<&str>,
+    ) -> Self {
         use serde_json::Value as v;
 
-        let mut event = Self {
-            event_type: String::from(event_type),
-            library: Some(String::from("Edgee")),
-            platform: Some(String::from("Web")),
-            ..Self::default()
-        };
-
-        let mut user_props = serde_json::Map::new();
-
-        // identify
-        if !edgee_event.context.user.user_id.is_empty() {
-            event.user_id = Option::from(edgee_event.context.user.user_id.clone());
-        }
-        if !edgee_event.context.user.anonymous_id.is_empty() {
-            user_props.insert(
-                "anonymous_id".to_string(),
-                v::String(edgee_event.context.user.anonymous_id.clone()),
-            );
-        }
-
-        // set edgee_id as device_id
-        event.device_id = Option::from(edgee_event.context.user.edgee_id.clone());
+        let mut event = Self::without_attribution(event_type, edgee_event, session_id, device_id);
 
         // set user_props HashMap<String, v>
         let mut set_user_props = serde_json::Map::new();
@@ -172,8 +418,9 @@ impl AmplitudeEvent {
                 v::String(edgee_event.context.page.referrer.clone()),
             );
 
-            let parsed_referrer = url::Url::parse(&edgee_event.context.page.referrer)?;
-            if let Some(referring_domain) = parsed_referrer.domain() {
+            // an unparseable referrer is reported in strict mode only
+            let parsed_referrer = url::Url::parse(&edgee_event.context.page.referrer).ok();
+            if let Some(referring_domain) = parsed_referrer.as_ref().and_then(|url| url.domain()) {
                 set_user_props.insert(
                     "referring_domain".to_string(),
                     v::String(referring_domain.to_string()),
@@ -237,22 +484,65 @@ impl AmplitudeEvent {
             );
         }
 
-        user_props.insert(
-            "$set".to_string(),
-            serde_json::to_value(set_user_props).unwrap_or_default(),
-        );
-        user_props.insert(
-            "$setOnce".to_string(),
-            serde_json::to_value(set_once_user_props).unwrap_or_default(),
-        );
+        // the context traits win over the attribution
+        for (key, value) in set_user_props {
+            if event.user_property("$set", &key).is_none() {
+                event.set_user_property(&key, value);
+            }
+        }
+        let user_props = event
+            .user_properties
+            .get_or_insert_with(|| v::Object(serde_json::Map::new()));
+        if let Some(user_props) = user_props.as_object_mut() {
+            user_props
+                .entry("$set")
+                .or_insert_with(|| v::Object(serde_json::Map::new()));
+            user_props
+                .entry("$setOnce")
+                .or_insert(serde_json::to_value(set_once_user_props).unwrap_or_default());
+        }
+
+        event
+    }
+
+    /// Builds an event carrying the identity and client context of the Edgee
+    /// event, without the referrer and campaign attribution of the current page.
+    pub(crate) fn without_attribution(
+        event_type: &str,
+        edgee_event: &Event,
+        session_id: u64,
+        device_id: Option<&str>,
+    ) -> Self {
+        use serde_json::Value as v;
+
+        let mut event = Self {
+            event_type: String::from(event_type),
+            library: Some(String::from("Edgee")),
+            platform: Some(String::from("Web")),
+            ..Self::default()
+        };
+
+        // identify
+        if !edgee_event.context.user.user_id.is_empty() {
+            event.user_id = Option::from(edgee_event.context.user.user_id.clone());
+        }
+
+        // the user properties are all sent with `$set`, see `set_user_property`
+        event.user_properties = Some(v::Object(serde_json::Map::new()));
+        if !edgee_event.context.user.anonymous_id.is_empty() {
+            event.set_user_property(
+                "anonymous_id",
+                v::String(edgee_event.context.user.anonymous_id.clone()),
+            );
+        }
+
+        // device_id, resolved from the amplitude_device_id_source setting
+        event.device_id = device_id.map(String::from);
 
         // add custom user properties
-        if !edgee_event.context.user.properties.is_empty() {
-            for (key, value) in edgee_event.context.user.properties.clone().iter() {
-                user_props.insert(key.clone(), parse_value(value));
-            }
+        for (key, value) in &edgee_event.context.user.properties {
+            event.set_user_property(key, parse_value(value));
         }
-        event.user_properties = Some(serde_json::to_value(user_props)?);
 
         event.user_agent = Option::from(edgee_event.context.client.user_agent.clone());
         event.language = Option::from(edgee_event.context.client.locale.clone());
@@ -300,8 +590,215 @@ impl AmplitudeEvent {
         // missing event.event_id
         // missing event.plan
 
-        Ok(event)
+        event
+    }
+}
+
+impl AmplitudeEvent {
+    /// Records in the trace the Edgee field each field of the event is read
+    /// from, unless the handler already recorded it. The device id depends
+    /// on its source setting, it is traced apart.
+    pub(crate) fn trace_sources(&self, trace: &mut Trace, edgee_event: &Event) {
+        let is_set = |field: &Option<String>| field.as_ref().is_some_and(|v| !v.is_empty());
+        for (field, set, source) in [
+            ("user_id", is_set(&self.user_id), "context.user.user_id"),
+            ("time", true, "timestamp"),
+            (
+                "session_id",
+                self.session_id.is_some(),
+                "context.session.session_id",
+            ),
+            ("ip", is_set(&self.ip), "context.client.ip"),
+            ("language", is_set(&self.language), "context.client.locale"),
+            (
+                "user_agent",
+                is_set(&self.user_agent),
+                "context.client.user_agent",
+            ),
+            ("os_name", is_set(&self.os_name), "context.client.os_name"),
+            (
+                "os_version",
+                is_set(&self.os_version),
+                "context.client.os_version",
+            ),
+            (
+                "device_model",
+                is_set(&self.device_model),
+                "context.client.user_agent_model",
+            ),
+            ("city", is_set(&self.city), "context.client.city"),
+            ("region", is_set(&self.region), "context.client.region"),
+            (
+                "country",
+                is_set(&self.country),
+                "context.client.country_code",
+            ),
+        ] {
+            if set {
+                trace.fallback_source(field, source);
+            }
+        }
+
+        let Some(serde_json::Value::Object(user_props)) = &self.user_properties else {
+            return;
+        };
+        if self.user_property("$set", "anonymous_id").is_some() {
+            trace.fallback_source(
+                "user_properties.$set.anonymous_id",
+                "context.user.anonymous_id",
+            );
+        }
+        for (key, _) in &edgee_event.context.user.properties {
+            if self.user_property("$set", key).is_some() {
+                trace.fallback_source(
+                    &format!("user_properties.$set.{key}"),
+                    &format!("context.user.properties.{key}"),
+                );
+            }
+        }
+        for (operation, prefix) in [("$set", ""), ("$setOnce", "initial_")] {
+            let Some(serde_json::Value::Object(properties)) = user_props.get(operation) else {
+                continue;
+            };
+            for (key, source) in [
+                ("referrer", "context.page.referrer"),
+                ("referring_domain", "context.page.referrer"),
+                ("utm_campaign", "context.campaign.name"),
+                ("utm_source", "context.campaign.source"),
+                ("utm_medium", "context.campaign.medium"),
+                ("utm_term", "context.campaign.term"),
+                ("utm_content", "context.campaign.content"),
+            ] {
+                let key = format!("{prefix}{key}");
+                if properties.contains_key(&key) {
+                    trace.fallback_source(&format!("user_properties.{operation}.{key}"), source);
+                }
+            }
+        }
+    }
+
+    /// Adds a property to the event properties.
+    pub(crate) fn insert_event_property(&mut self, key: &str, value: serde_json::Value) {
+        let event_props = self
+            .event_properties
+            .get_or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+        if let Some(event_props) = event_props.as_object_mut() {
+            event_props.insert(key.to_string(), value);
+        }
+    }
+
+    pub(crate) fn event_property(&self, key: &str) -> Option<&serde_json::Value> {
+        self.event_properties.as_ref()?.get(key)
+    }
+
+    /// Links the event to a Session Replay recording, which Amplitude joins
+    /// on `<device_id>/<session_id>`.
+    pub(crate) fn link_session_replay(&mut self) {
+        if let (Some(device_id), Some(session_id)) = (&self.device_id, self.session_id) {
+            if !device_id.is_empty() {
+                let replay_id = format!("{device_id}/{session_id}");
+                self.insert_event_property(
+                    "[Amplitude] Session Replay ID",
+                    serde_json::Value::String(replay_id),
+                );
+            }
+        }
+    }
+
+    /// Property of one operation of the user properties, such as `$set`.
+    pub(crate) fn user_property(&self, operation: &str, key: &str) -> Option<&serde_json::Value> {
+        self.user_properties.as_ref()?.get(operation)?.get(key)
+    }
+
+    /// Adds a property to the `$set` operation of the user properties.
+    ///
+    /// User properties are only ever sent through operations: Amplitude
+    /// ignores plain user properties sent next to `$set` or `$setOnce`.
+    pub(crate) fn set_user_property(&mut self, key: &str, value: serde_json::Value) {
+        self.user_property_operation("$set", key, value);
+    }
+
+    /// Adds a property to the `$setOnce` operation of the user properties.
+    pub(crate) fn set_once_user_property(&mut self, key: &str, value: serde_json::Value) {
+        self.user_property_operation("$setOnce", key, value);
+    }
+
+    fn user_property_operation(&mut self, operation: &str, key: &str, value: serde_json::Value) {
+        let user_props = self
+            .user_properties
+            .get_or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+        if let Some(user_props) = user_props.as_object_mut() {
+            let properties = user_props
+                .entry(operation)
+                .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+            if let Some(properties) = properties.as_object_mut() {
+                properties.insert(key.to_string(), value);
+            }
+        }
+    }
+
+    /// Sets one of the [`EVENT_FIELDS`]. Returns false when the value doesn't
+    /// fit the type of the field.
+    pub(crate) fn set_field(&mut self, name: &str, value: &serde_json::Value) -> bool {
+        let text = match value {
+            serde_json::Value::String(value) => value.clone(),
+            other => other.to_string(),
+        };
+        let float = || {
+            value
+                .as_f64()
+                .or_else(|| text.trim().parse::<f64>().ok())
+                .filter(|number| number.is_finite())
+                .map(|number| number as f32)
+        };
+        let integer = || {
+            value
+                .as_i64()
+                .or_else(|| text.trim().parse::<i64>().ok())
+                .and_then(|number| i32::try_from(number).ok())
+        };
+
+        let field = match name {
+            "price" => return set(&mut self.price, float()),
+            "revenue" => return set(&mut self.revenue, float()),
+            "location_lat" => return set(&mut self.location_lat, float()),
+            "location_lng" => return set(&mut self.location_lng, float()),
+            "quantity" => return set(&mut self.quantity, integer()),
+            "event_id" => return set(&mut self.event_id, integer()),
+            "app_version" => &mut self.app_version,
+            "platform" => &mut self.platform,
+            "os_name" => &mut self.os_name,
+            "os_version" => &mut self.os_version,
+            "device_brand" => &mut self.device_brand,
+            "device_manufacturer" => &mut self.device_manufacturer,
+            "device_model" => &mut self.device_model,
+            "carrier" => &mut self.carrier,
+            "country" => &mut self.country,
+            "region" => &mut self.region,
+            "city" => &mut self.city,
+            "dma" => &mut self.dma,
+            "language" => &mut self.language,
+            "product_id" => &mut self.product_id,
+            "revenue_type" => &mut self.revenue_type,
+            "ip" => &mut self.ip,
+            "idfa" => &mut self.idfa,
+            "idfv" => &mut self.idfv,
+            "adid" => &mut self.adid,
+            "android_id" => &mut self.android_id,
+            "user_agent" => &mut self.user_agent,
+            _ => return false,
+        };
+        *field = Some(text);
+        true
+    }
+}
+
+fn set<T>(field: &mut Option<T>, value: Option<T>) -> bool {
+    let converted = value.is_some();
+    if converted {
+        *field = value;
     }
+    converted
 }
 
 pub fn parse_value(value: &str) -> serde_json::Value {
@@ -309,8 +806,8 @@ pub fn parse_value(value: &str) -> serde_json::Value {
         serde_json::Value::from(true)
     } else if value == "false" {
         serde_json::Value::from(false)
-    } else if let Ok(_v) = value.parse::<f64>() {
-        serde_json::Value::Number(value.parse().unwrap())
+    } else if let Ok(number) = value.parse::<serde_json::Number>() {
+        serde_json::Value::Number(number)
     } else {
         serde_json::Value::String(value.to_string())
     }
@@ -0,0 +1,107 @@
+//! Mapping of Edgee track events to the events produced by the autocapture
+//! of Amplitude's Browser SDK, so that the built-in autocapture charts work
+//! with Edgee-collected traffic.
+
+/// An autocapture event and the Edgee track events it is built from.
+pub(crate) struct Preset {
+    /// Edgee event names recognized by the preset, compared case-insensitively.
+    edgee_events: &'static [&'static str],
+    /// Canonical Amplitude event name.
+    pub(crate) event_type: &'static str,
+    /// Edgee property names and the Amplitude property they are renamed to.
+    properties: &'static [(&'static str, &'static str)],
+}
+
+const FORM_PROPERTIES: &[(&str, &str)] = &[
+    ("form_id", "[Amplitude] Form ID"),
+    ("form_name", "[Amplitude] Form Name"),
+    ("form_destination", "[Amplitude] Form Destination"),
+];
+
+const PRESETS: &[Preset] = &[
+    Preset {
+        edgee_events: &["click", "element_click", "element_clicked"],
+        event_type: "[Amplitude] Element Clicked",
+        properties: &[
+            ("element_id", "[Amplitude] Element ID"),
+            ("element_class", "[Amplitude] Element Class"),
+            ("element_tag", "[Amplitude] Element Tag"),
+            ("element_text", "[Amplitude] Element Text"),
+            ("element_href", "[Amplitude] Element Href"),
+            ("element_selector", "[Amplitude] Element Selector"),
+            ("element_position", "[Amplitude] Element Position"),
+            ("element_aria_label", "[Amplitude] Element Aria Label"),
+            ("element_parent_label", "[Amplitude] Element Parent Label"),
+        ],
+    },
+    Preset {
+        edgee_events: &["form_start", "form_started"],
+        event_type: "[Amplitude] Form Started",
+        properties: FORM_PROPERTIES,
+    },
+    Preset {
+        edgee_events: &["form_submit", "form_submitted"],
+        event_type: "[Amplitude] Form Submitted",
+        properties: FORM_PROPERTIES,
+    },
+    Preset {
+        edgee_events: &["file_download", "file_downloaded"],
+        event_type: "[Amplitude] File Downloaded",
+        properties: &[
+            ("file_extension", "[Amplitude] File Extension"),
+            ("file_name", "[Amplitude] File Name"),
+            ("link_id", "[Amplitude] Link ID"),
+            ("link_text", "[Amplitude] Link Text"),
+            ("link_url", "[Amplitude] Link URL"),
+        ],
+    },
+];
+
+/// Returns the autocapture preset matching an Edgee track event name.
+pub(crate) fn preset(event_name: &str) -> Option<&'static Preset> {
+    let event_name = event_name.trim().to_lowercase().replace([' ', '-'], "_");
+    PRESETS
+        .iter()
+        .find(|preset| preset.edgee_events.contains(&event_name.as_str()))
+}
+
+impl Preset {
+    /// Returns the canonical name of an Edgee property, or the name itself
+    /// when the preset doesn't rename it.
+    pub(crate) fn property_name<'a>(&self, key: &'a str) -> &'a str {
+        self.properties
+            .iter()
+            .find(|(edgee_name, _)| *edgee_name == key)
+            .map(|(_, amplitude_name)| *amplitude_name)
+            .unwrap_or(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_edgee_event_names() {
+        assert_eq!(
+            preset("click").unwrap().event_type,
+            "[Amplitude] Element Clicked"
+        );
+        assert_eq!(
+            preset("Form Submitted").unwrap().event_type,
+            "[Amplitude] Form Submitted"
+        );
+        assert_eq!(
+            preset("file-download").unwrap().event_type,
+            "[Amplitude] File Downloaded"
+        );
+        assert!(preset("purchase").is_none());
+    }
+
+    #[test]
+    fn renames_known_properties_only() {
+        let form_started = preset("form_start").unwrap();
+        assert_eq!(form_started.property_name("form_id"), "[Amplitude] Form ID");
+        assert_eq!(form_started.property_name("step"), "step");
+    }
+}
@@ -0,0 +1,177 @@
+//! Detection of bots, crawlers, uptime monitors and headless browsers from
+//! the user agent of the client.
+
+/// Lowercase user agent fragments of known bots. The generic `bot` is
+/// matched apart, see [`has_bot_token`].
+const BOT_SIGNATURES: &[&str] = &[
+    // generic
+    "crawler",
+    "spider",
+    "scraper",
+    "slurp",
+    "archiver",
+    // search engines and social previews
+    "mediapartners-google",
+    "adsbot-google",
+    "google-inspectiontool",
+    "bingpreview",
+    "yandex",
+    "baiduspider",
+    "facebookexternalhit",
+    "facebookcatalog",
+    "embedly",
+    "quora link preview",
+    "outbrain",
+    "vkshare",
+    "w3c_validator",
+    "skypeuripreview",
+    // uptime monitors and SEO tools
+    "pingdom",
+    "uptimerobot",
+    "statuscake",
+    "site24x7",
+    "newrelicpinger",
+    "datadog synthetic",
+    "checkly",
+    "lighthouse",
+    "pagespeed",
+    "gtmetrix",
+    "ahrefs",
+    "semrush",
+    "screaming frog",
+    // headless browsers and automation
+    "headlesschrome",
+    "phantomjs",
+    "puppeteer",
+    "playwright",
+    "selenium",
+    "webdriver",
+    // http clients
+    "curl/",
+    "wget/",
+    "python-requests",
+    "python-urllib",
+    "aiohttp",
+    "go-http-client",
+    "java/",
+    "okhttp",
+    "axios/",
+    "node-fetch",
+    "libwww-perl",
+    "httpclient",
+    "postmanruntime",
+    "insomnia",
+];
+
+/// Characters ending a `bot` product token, such as `Googlebot/2.1`,
+/// `PetalBot;` or `Slackbot-LinkExpanding`.
+const BOT_TOKEN_ENDS: &[char] = &['/', ';', ')', '(', '-', '@', '.', ',', '+', ':'];
+
+/// Whether the lowercase user agent has a product token ending with `bot`.
+/// A bare `bot` substring would also match device models such as the
+/// Android `CUBOT_X30` or `CUBOT P50`.
+fn has_bot_token(user_agent: &str) -> bool {
+    user_agent.match_indices("bot").any(|(index, _)| {
+        user_agent[index + 3..]
+            .chars()
+            .next()
+            .is_some_and(|next| BOT_TOKEN_ENDS.contains(&next))
+    })
+}
+
+/// What happens to the events of bots.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum BotPolicy {
+    /// Events are not sent.
+    Drop,
+    /// Events are sent with the `is_bot` event property.
+    Flag,
+}
+
+/// Bot detection, with the policy applied to their events.
+#[derive(Debug)]
+pub(crate) struct BotFilter {
+    pub(crate) policy: BotPolicy,
+    patterns: Vec<String>,
+}
+
+impl BotFilter {
+    pub(crate) fn new(policy: BotPolicy, patterns: &[String]) -> Self {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| pattern.to_lowercase())
+            .collect();
+        Self { policy, patterns }
+    }
+
+    /// Whether the user agent belongs to a bot, compared case-insensitively
+    /// with the compiled-in signatures and the custom patterns.
+    pub(crate) fn matches(&self, user_agent: &str) -> bool {
+        let user_agent = user_agent.to_lowercase();
+        has_bot_token(&user_agent)
+            || BOT_SIGNATURES
+                .iter()
+                .copied()
+                .chain(self.patterns.iter().map(String::as_str))
+                .any(|signature| user_agent.contains(signature))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(patterns: &[&str]) -> BotFilter {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        BotFilter::new(BotPolicy::Flag, &patterns)
+    }
+
+    #[test]
+    fn recognizes_bots() {
+        let filter = filter(&[]);
+        assert!(filter
+            .matches("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"));
+        assert!(filter.matches("Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) HeadlessChrome/120.0.0.0 Safari/537.36"));
+        assert!(filter.matches("Pingdom.com_bot_version_1.4"));
+        assert!(filter.matches("curl/8.4.0"));
+        assert!(!filter.matches("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36"));
+        assert!(!filter.matches(""));
+    }
+
+    #[test]
+    fn recognizes_bot_tokens() {
+        let filter = filter(&[]);
+        for user_agent in [
+            "Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)",
+            "Mozilla/5.0 (compatible; AhrefsBot/7.0; +http://ahrefs.com/robot/)",
+            "Mozilla/5.0 (Linux; Android 7.0;) AppleWebKit/537.36 (KHTML, like Gecko) Mobile Safari/537.36 (compatible; PetalBot;+https://webmaster.petalsearch.com/site/petalbot)",
+            "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)",
+            "Mozilla/5.0 AppleWebKit/537.36 (KHTML, like Gecko; compatible; GPTBot/1.0; +https://openai.com/gptbot)",
+            "DuckDuckBot-Https/1.1; (+https://duckduckgo.com/duckduckbot)",
+            "Twitterbot/1.0",
+        ] {
+            assert!(filter.matches(user_agent), "{user_agent}");
+        }
+    }
+
+    #[test]
+    fn ignores_device_models_containing_bot() {
+        let filter = filter(&[]);
+        for user_agent in [
+            "Mozilla/5.0 (Linux; Android 10; CUBOT_X30) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Mobile Safari/537.36",
+            "Mozilla/5.0 (Linux; Android 12; CUBOT P50 Build/SP1A.210812.016) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/125.0.0.0 Mobile Safari/537.36",
+            "Mozilla/5.0 (Linux; Android 11; KINGKONG 7 Build/RP1A.200720.011; wv) AppleWebKit/537.36 (KHTML, like Gecko) Version/4.0 Chrome/120.0.0.0 Mobile Safari/537.36 CUBOT",
+            "Mozilla/5.0 (Linux; Android 13; Robotics Tablet) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36",
+        ] {
+            assert!(!filter.matches(user_agent), "{user_agent}");
+        }
+    }
+
+    #[test]
+    fn custom_patterns_are_case_insensitive() {
+        let filter = filter(&["Internal-Monitor", "LoadTester"]);
+        assert!(filter.matches("internal-monitor/1.0"));
+        assert!(filter.matches("LOADTESTER"));
+        assert!(!filter.matches("Mozilla/5.0"));
+    }
+}
@@ -0,0 +1,224 @@
+//! Resolution of the Amplitude `device_id` from the Edgee event.
+//!
+//! The `amplitude_device_id_source` setting is a comma-separated list of
+//! sources tried in order, the first one giving a non-empty value wins:
+//! - `edgee_id`: the Edgee id of the user (the default)
+//! - `anonymous_id`: the anonymous id of the user
+//! - `user_property:<name>`: a property of the user
+//! - `event_property:<name>`: a property of the page or track event
+//! - `hash:<source>+<source>...`: a hash of the other sources, skipped when
+//!   one of them is empty
+
+use anyhow::anyhow;
+
+use crate::exports::edgee::components::data_collection::{Data, Dict, Event};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    EdgeeId,
+    AnonymousId,
+    UserProperty(String),
+    EventProperty(String),
+    Hash(Vec<Source>),
+}
+
+/// Ordered list of the sources of the device id.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct DeviceIdSource(Vec<Source>);
+
+impl Default for DeviceIdSource {
+    fn default() -> Self {
+        Self(vec![Source::EdgeeId])
+    }
+}
+
+impl DeviceIdSource {
+    pub(crate) fn parse(sources: &[String]) -> anyhow::Result<Self> {
+        let sources = sources
+            .iter()
+            .map(|source| Source::parse(source))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if sources.is_empty() {
+            return Ok(Self::default());
+        }
+        Ok(Self(sources))
+    }
+
+    /// Returns the device id of the event, or `None` when every source is empty.
+    pub(crate) fn resolve(&self, edgee_event: &Event) -> Option<String> {
+        self.0
+            .iter()
+            .filter_map(|source| source.resolve(edgee_event))
+            .find(|device_id| !device_id.is_empty())
+    }
+
+    /// Edgee field the device id of the event is read from, for the trace.
+    pub(crate) fn source(&self, edgee_event: &Event) -> Option<String> {
+        self.0
+            .iter()
+            .find(|source| {
+                source
+                    .resolve(edgee_event)
+                    .is_some_and(|device_id| !device_id.is_empty())
+            })
+            .map(Source::path)
+    }
+}
+
+impl Source {
+    fn parse(value: &str) -> anyhow::Result<Self> {
+        let invalid = || {
+            anyhow!(
+                "Invalid amplitude_device_id_source {value:?}, expected edgee_id, anonymous_id, user_property:<name>, event_property:<name> or hash:<source>+<source>"
+            )
+        };
+
+        match value.split_once(':') {
+            None => match value {
+                "edgee_id" => Ok(Source::EdgeeId),
+                "anonymous_id" => Ok(Source::AnonymousId),
+                _ => Err(invalid()),
+            },
+            Some(("user_property", name)) if !name.trim().is_empty() => {
+                Ok(Source::UserProperty(name.trim().to_string()))
+            }
+            Some(("event_property", name)) if !name.trim().is_empty() => {
+                Ok(Source::EventProperty(name.trim().to_string()))
+            }
+            Some(("hash", sources)) => {
+                let sources = sources
+                    .split('+')
+                    .map(|source| Source::parse(source.trim()))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                if sources.is_empty() || sources.iter().any(|s| matches!(s, Source::Hash(_))) {
+                    return Err(invalid());
+                }
+                Ok(Source::Hash(sources))
+            }
+            _ => Err(invalid()),
+        }
+    }
+
+    fn path(&self) -> String {
+        match self {
+            Source::EdgeeId => String::from("context.user.edgee_id"),
+            Source::AnonymousId => String::from("context.user.anonymous_id"),
+            Source::UserProperty(name) => format!("user.properties.{name}"),
+            Source::EventProperty(name) => format!("data.properties.{name}"),
+            Source::Hash(sources) => {
+                let paths: Vec<String> = sources.iter().map(Source::path).collect();
+                format!("hash({})", paths.join("+"))
+            }
+        }
+    }
+
+    fn resolve(&self, edgee_event: &Event) -> Option<String> {
+        let user = &edgee_event.context.user;
+        match self {
+            Source::EdgeeId => Some(user.edgee_id.clone()),
+            Source::AnonymousId => Some(user.anonymous_id.clone()),
+            Source::UserProperty(name) => {
+                let user_data_properties = match &edgee_event.data {
+                    Data::User(data) => Some(&data.properties),
+                    _ => None,
+                };
+                user_data_properties
+                    .and_then(|properties| property(properties, name))
+                    .or_else(|| property(&user.properties, name))
+            }
+            Source::EventProperty(name) => match &edgee_event.data {
+                Data::Page(data) => property(&data.properties, name),
+                Data::Track(data) => property(&data.properties, name),
+                Data::User(_) => None,
+            },
+            Source::Hash(sources) => {
+                let values = sources
+                    .iter()
+                    .map(|source| source.resolve(edgee_event).filter(|v| !v.is_empty()))
+                    .collect::<Option<Vec<_>>>()?;
+                Some(format!("{:016x}", hash_values(&values)))
+            }
+        }
+    }
+}
+
+fn property(properties: &Dict, name: &str) -> Option<String> {
+    properties
+        .iter()
+        .find(|(key, value)| key == name && !value.is_empty())
+        .map(|(_, value)| value.clone())
+}
+
+/// 64-bit FNV-1a hash, stable across builds and platforms.
+pub(crate) fn hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Hash of several values, each prefixed with its length so that no two
+/// different lists of values share the same input.
+pub(crate) fn hash_values<S: AsRef<str>>(values: &[S]) -> u64 {
+    let mut bytes = Vec::new();
+    for value in values {
+        let value = value.as_ref().as_bytes();
+        bytes.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(value);
+    }
+    hash(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(sources: &[&str]) -> anyhow::Result<DeviceIdSource> {
+        let sources: Vec<String> = sources.iter().map(|s| s.to_string()).collect();
+        DeviceIdSource::parse(&sources)
+    }
+
+    #[test]
+    fn parses_sources_in_order() {
+        let source = parse(&["user_property:amp_device_id", "anonymous_id", "edgee_id"]).unwrap();
+        assert_eq!(
+            source,
+            DeviceIdSource(vec![
+                Source::UserProperty("amp_device_id".to_string()),
+                Source::AnonymousId,
+                Source::EdgeeId,
+            ])
+        );
+        assert_eq!(parse(&[]).unwrap(), DeviceIdSource::default());
+    }
+
+    #[test]
+    fn parses_hashed_combinations() {
+        assert_eq!(
+            parse(&["hash:anonymous_id+event_property:account"]).unwrap(),
+            DeviceIdSource(vec![Source::Hash(vec![
+                Source::AnonymousId,
+                Source::EventProperty("account".to_string()),
+            ])])
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_sources() {
+        assert!(parse(&["cookie"]).is_err());
+        assert!(parse(&["user_property:"]).is_err());
+        assert!(parse(&["hash:edgee_id+hash:anonymous_id"]).is_err());
+    }
+
+    #[test]
+    fn hash_is_stable() {
+        assert_eq!(hash(b""), 0xcbf29ce484222325);
+        assert_eq!(hash(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn hashed_values_do_not_collide_on_separators() {
+        assert_ne!(hash_values(&["a+b", "c"]), hash_values(&["a", "b+c"]));
+        assert_ne!(hash_values(&["ab", ""]), hash_values(&["a", "b"]));
+    }
+}
@@ -0,0 +1,94 @@
+//! Errors returned by the component. Each kind has a stable code, so they can
+//! be told apart in the Edgee logs.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Error {
+    /// The settings of the component are missing or invalid.
+    Settings(String),
+    /// The event lacks data required to build the request.
+    MissingData(String),
+    /// A field of the event can't be parsed.
+    InvalidField { field: String, value: String },
+    /// The event is deliberately not sent: internal traffic, bots, sampling.
+    Filtered(String),
+}
+
+impl Error {
+    pub(crate) fn invalid_field(field: &str, value: &str) -> Self {
+        Error::InvalidField {
+            field: field.to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    /// Stable code of the error kind.
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Error::Settings(_) => "settings",
+            Error::MissingData(_) => "missing_data",
+            Error::InvalidField { .. } => "invalid_field",
+            Error::Filtered(_) => "filtered",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Settings(message) | Error::MissingData(message) | Error::Filtered(message) => {
+                write!(f, "{}: {message}", self.code())
+            }
+            Error::InvalidField { field, value } => {
+                write!(f, "{}: Invalid {field} {value:?}", self.code())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Settings are parsed with `anyhow`, its errors are settings errors.
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        Error::Settings(error.to_string())
+    }
+}
+
+impl From<Error> for String {
+    fn from(error: Error) -> Self {
+        error.to_string()
+    }
+}
+
+/// Which issues with the fields of an event are fatal.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) enum ErrorMode {
+    /// Invalid optional fields are skipped, the event is still sent.
+    #[default]
+    Lenient,
+    /// Any invalid field fails the event.
+    Strict,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_start_with_the_code() {
+        assert_eq!(
+            String::from(Error::MissingData("Missing event name".to_string())),
+            "missing_data: Missing event name"
+        );
+        assert_eq!(
+            Error::invalid_field("referrer", "not a url").to_string(),
+            "invalid_field: Invalid referrer \"not a url\""
+        );
+        assert_eq!(
+            Error::from(anyhow::anyhow!("Missing amplitude_api_key")).code(),
+            "settings"
+        );
+    }
+}
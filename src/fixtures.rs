@@ -0,0 +1,323 @@
+//! Golden-file fixtures shared by the native snapshot tests and the wasm
+//! harness in `tests/wasm`, which includes this file with `#[path]`.
+//!
+//! Both crates expose the WIT types under `crate::exports`, so the fixtures
+//! turn into the same events on either side of the component boundary.
+
+use crate::exports::edgee::components::data_collection::{
+    Campaign, Client, Consent, Context, Data, Dict, EdgeeRequest, Event, EventType, HttpMethod,
+    PageData, Session, TrackData, UserData,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct EventFixture {
+    timestamp: i64,
+    event_type: String,
+    data: Value,
+    context: ContextFixture,
+    consent: Option<String>,
+}
+
+impl Default for EventFixture {
+    fn default() -> Self {
+        Self {
+            timestamp: 1_700_000_000,
+            event_type: String::from("page"),
+            data: json!({}),
+            context: ContextFixture::default(),
+            consent: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ContextFixture {
+    page: PageFixture,
+    user: UserFixture,
+    client: ClientFixture,
+    campaign: CampaignFixture,
+    session: SessionFixture,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct PageFixture {
+    name: String,
+    category: String,
+    keywords: Vec<String>,
+    title: String,
+    url: String,
+    path: String,
+    search: String,
+    referrer: String,
+    properties: Vec<(String, String)>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct UserFixture {
+    user_id: String,
+    anonymous_id: String,
+    edgee_id: String,
+    properties: Vec<(String, String)>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct TrackFixture {
+    name: String,
+    properties: Vec<(String, String)>,
+    products: Vec<Vec<(String, String)>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ClientFixture {
+    ip: String,
+    locale: String,
+    timezone: String,
+    user_agent: String,
+    user_agent_architecture: String,
+    user_agent_bitness: String,
+    user_agent_version_list: String,
+    user_agent_full_version_list: String,
+    user_agent_mobile: String,
+    user_agent_model: String,
+    os_name: String,
+    os_version: String,
+    screen_width: i32,
+    screen_height: i32,
+    screen_density: f32,
+    continent: String,
+    country_code: String,
+    country_name: String,
+    region: String,
+    city: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct CampaignFixture {
+    name: String,
+    source: String,
+    medium: String,
+    term: String,
+    content: String,
+    creative_format: String,
+    marketing_tactic: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SessionFixture {
+    session_id: String,
+    previous_session_id: String,
+    session_count: u32,
+    session_start: bool,
+    first_seen: i64,
+    last_seen: i64,
+}
+
+impl From<PageFixture> for PageData {
+    fn from(page: PageFixture) -> Self {
+        PageData {
+            name: page.name,
+            category: page.category,
+            keywords: page.keywords,
+            title: page.title,
+            url: page.url,
+            path: page.path,
+            search: page.search,
+            referrer: page.referrer,
+            properties: page.properties,
+        }
+    }
+}
+
+impl From<UserFixture> for UserData {
+    fn from(user: UserFixture) -> Self {
+        UserData {
+            user_id: user.user_id,
+            anonymous_id: user.anonymous_id,
+            edgee_id: user.edgee_id,
+            properties: user.properties,
+        }
+    }
+}
+
+impl From<TrackFixture> for TrackData {
+    fn from(track: TrackFixture) -> Self {
+        TrackData {
+            name: track.name,
+            properties: track.properties,
+            products: track.products,
+        }
+    }
+}
+
+impl From<ClientFixture> for Client {
+    fn from(client: ClientFixture) -> Self {
+        Client {
+            ip: client.ip,
+            locale: client.locale,
+            timezone: client.timezone,
+            user_agent: client.user_agent,
+            user_agent_architecture: client.user_agent_architecture,
+            user_agent_bitness: client.user_agent_bitness,
+            user_agent_version_list: client.user_agent_version_list,
+            user_agent_full_version_list: client.user_agent_full_version_list,
+            user_agent_mobile: client.user_agent_mobile,
+            user_agent_model: client.user_agent_model,
+            os_name: client.os_name,
+            os_version: client.os_version,
+            screen_width: client.screen_width,
+            screen_height: client.screen_height,
+            screen_density: client.screen_density,
+            continent: client.continent,
+            country_code: client.country_code,
+            country_name: client.country_name,
+            region: client.region,
+            city: client.city,
+        }
+    }
+}
+
+impl From<CampaignFixture> for Campaign {
+    fn from(campaign: CampaignFixture) -> Self {
+        Campaign {
+            name: campaign.name,
+            source: campaign.source,
+            medium: campaign.medium,
+            term: campaign.term,
+            content: campaign.content,
+            creative_format: campaign.creative_format,
+            marketing_tactic: campaign.marketing_tactic,
+        }
+    }
+}
+
+impl From<SessionFixture> for Session {
+    fn from(session: SessionFixture) -> Self {
+        Session {
+            session_id: session.session_id,
+            previous_session_id: session.previous_session_id,
+            session_count: session.session_count,
+            session_start: session.session_start,
+            first_seen: session.first_seen,
+            last_seen: session.last_seen,
+        }
+    }
+}
+
+impl EventFixture {
+    fn into_event(self, name: &str) -> Event {
+        let (event_type, data) = match self.event_type.as_str() {
+            "page" => (
+                EventType::Page,
+                Data::Page(parse_json::<PageFixture>(name, self.data).into()),
+            ),
+            "track" => (
+                EventType::Track,
+                Data::Track(parse_json::<TrackFixture>(name, self.data).into()),
+            ),
+            "user" => (
+                EventType::User,
+                Data::User(parse_json::<UserFixture>(name, self.data).into()),
+            ),
+            other => panic!("{name}: unknown event_type {other:?}"),
+        };
+
+        let consent = self.consent.map(|consent| match consent.as_str() {
+            "pending" => Consent::Pending,
+            "granted" => Consent::Granted,
+            "denied" => Consent::Denied,
+            other => panic!("{name}: unknown consent {other:?}"),
+        });
+
+        Event {
+            uuid: format!("{name}-uuid"),
+            timestamp: self.timestamp,
+            timestamp_millis: self.timestamp * 1000,
+            timestamp_micros: self.timestamp * 1_000_000,
+            event_type,
+            data,
+            context: Context {
+                page: self.context.page.into(),
+                user: self.context.user.into(),
+                client: self.context.client.into(),
+                campaign: self.context.campaign.into(),
+                session: self.context.session.into(),
+            },
+            consent,
+        }
+    }
+}
+
+fn parse_json<T: for<'de> Deserialize<'de>>(name: &str, value: Value) -> T {
+    serde_json::from_value(value).unwrap_or_else(|e| panic!("{name}: invalid fixture: {e}"))
+}
+
+pub(crate) fn read_json(path: &Path) -> Value {
+    let content = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("unable to read {}: {e}", path.display()));
+    serde_json::from_str(&content)
+        .unwrap_or_else(|e| panic!("invalid JSON in {}: {e}", path.display()))
+}
+
+fn read_settings(name: &str, path: &Path) -> Dict {
+    let Value::Object(settings) = read_json(path) else {
+        panic!("{name}: settings.json must be a JSON object");
+    };
+    settings
+        .into_iter()
+        .map(|(key, value)| match value {
+            Value::String(value) => (key, value),
+            value => (key, value.to_string()),
+        })
+        .collect()
+}
+
+pub(crate) fn request_to_json(request: Result<EdgeeRequest, String>) -> Value {
+    match request {
+        Ok(request) => json!({
+            "method": match request.method {
+                HttpMethod::Get => "GET",
+                HttpMethod::Put => "PUT",
+                HttpMethod::Post => "POST",
+                HttpMethod::Delete => "DELETE",
+            },
+            "url": request.url,
+            "headers": request.headers,
+            "forward_client_headers": request.forward_client_headers,
+            "body": serde_json::from_str::<Value>(&request.body)
+                .unwrap_or(Value::String(request.body)),
+        }),
+        Err(error) => json!({ "error": error }),
+    }
+}
+
+/// Loads the event and settings of a fixture directory.
+pub(crate) fn load_case(dir: &Path) -> (String, Event, Dict) {
+    let name = dir.file_name().unwrap().to_string_lossy().to_string();
+    let fixture: EventFixture = parse_json(&name, read_json(&dir.join("event.json")));
+    let settings = read_settings(&name, &dir.join("settings.json"));
+    let event = fixture.into_event(&name);
+    (name, event, settings)
+}
+
+/// Every fixture directory under `root`, sorted by name.
+pub(crate) fn fixture_dirs(root: &str) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = fs::read_dir(root)
+        .unwrap_or_else(|e| panic!("unable to read {root}: {e}"))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    dirs.sort();
+    dirs
+}
@@ -0,0 +1,234 @@
+//! Headers of the requests sent to Amplitude: which client headers are
+//! forwarded, and the extra static headers added by the settings.
+
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+
+use crate::exports::edgee::components::data_collection::Event;
+
+/// Client headers that can be allow-listed. The component doesn't see the
+/// client request, so they are rebuilt from the event context.
+const ALLOWED_CLIENT_HEADERS: [&str; 5] = [
+    "user-agent",
+    "accept-language",
+    "x-forwarded-for",
+    "referer",
+    "origin",
+];
+
+/// Which client headers are forwarded to Amplitude.
+#[derive(Debug, Default, PartialEq)]
+enum Forward {
+    /// Edgee forwards every client header.
+    #[default]
+    All,
+    /// No client header is forwarded.
+    None,
+    /// Only these headers are sent, rebuilt from the event context.
+    Allowlist(Vec<String>),
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct HeaderPolicy {
+    forward: Forward,
+    extra: Vec<(String, String)>,
+}
+
+impl HeaderPolicy {
+    pub(crate) fn parse(
+        forward: bool,
+        allowlist: &[String],
+        headers: &BTreeMap<String, String>,
+        secret_headers: &BTreeMap<String, String>,
+    ) -> anyhow::Result<Self> {
+        let allowlist: Vec<String> = allowlist.iter().map(|name| name.to_lowercase()).collect();
+        if let Some(name) = allowlist
+            .iter()
+            .find(|name| !ALLOWED_CLIENT_HEADERS.contains(&name.as_str()))
+        {
+            return Err(anyhow!(
+                "Invalid amplitude_client_headers_allowlist, {name:?} can't be forwarded, expected {}",
+                ALLOWED_CLIENT_HEADERS.join(", ")
+            ));
+        }
+
+        let forward = if !forward {
+            Forward::None
+        } else if allowlist.is_empty() {
+            Forward::All
+        } else {
+            Forward::Allowlist(allowlist)
+        };
+
+        let mut extra = parse_headers("amplitude_headers", headers)?;
+        extra.extend(parse_headers("amplitude_secret_headers", secret_headers)?);
+
+        Ok(Self { forward, extra })
+    }
+
+    /// Whether Edgee forwards every client header.
+    pub(crate) fn forward_client_headers(&self) -> bool {
+        self.forward == Forward::All
+    }
+
+    /// Allow-listed client headers, rebuilt from the event context.
+    pub(crate) fn client_headers(&self, edgee_event: &Event) -> Vec<(String, String)> {
+        let Forward::Allowlist(allowlist) = &self.forward else {
+            return vec![];
+        };
+
+        let client = &edgee_event.context.client;
+        let page_url = url::Url::parse(&edgee_event.context.page.url).ok();
+        allowlist
+            .iter()
+            .filter_map(|name| {
+                let value = match name.as_str() {
+                    "user-agent" => client.user_agent.clone(),
+                    "accept-language" => client.locale.clone(),
+                    "x-forwarded-for" => client.ip.clone(),
+                    "referer" => edgee_event.context.page.url.clone(),
+                    "origin" => page_url
+                        .as_ref()
+                        .map(|url| url.origin().ascii_serialization())
+                        .filter(|origin| origin != "null")
+                        .unwrap_or_default(),
+                    _ => String::new(),
+                };
+                (!value.is_empty()).then(|| (name.clone(), value))
+            })
+            .collect()
+    }
+
+    /// Adds the static headers to a request, replacing the headers of the
+    /// same name.
+    pub(crate) fn add_extra_headers(&self, headers: &mut Vec<(String, String)>) {
+        for (name, value) in &self.extra {
+            headers.retain(|(existing, _)| !existing.eq_ignore_ascii_case(name));
+            headers.push((name.clone(), value.clone()));
+        }
+    }
+}
+
+/// Headers the component sets itself: the credentials of deletion requests
+/// and the body format of every request.
+const RESERVED_HEADERS: [&str; 2] = ["authorization", "content-type"];
+
+/// Checks the header names and values of a setting.
+fn parse_headers(
+    setting: &str,
+    headers: &BTreeMap<String, String>,
+) -> anyhow::Result<Vec<(String, String)>> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let name = name.trim().to_lowercase();
+            let valid_name = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c));
+            if !valid_name || value.contains(['\r', '\n']) {
+                return Err(anyhow!("Invalid {setting}, header {name:?} is not valid"));
+            }
+            if RESERVED_HEADERS.contains(&name.as_str()) {
+                return Err(anyhow!(
+                    "Invalid {setting}, header {name:?} is set by the component"
+                ));
+            }
+            Ok((name, value.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    fn headers(json: &str) -> BTreeMap<String, String> {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn parse(forward: bool, allowlist: &[&str]) -> anyhow::Result<HeaderPolicy> {
+        HeaderPolicy::parse(
+            forward,
+            &list(allowlist),
+            &BTreeMap::new(),
+            &BTreeMap::new(),
+        )
+    }
+
+    #[test]
+    fn forwards_every_client_header_by_default() {
+        let policy = parse(true, &[]).unwrap();
+        assert!(policy.forward_client_headers());
+
+        let policy = parse(false, &[]).unwrap();
+        assert!(!policy.forward_client_headers());
+
+        let policy = parse(true, &["User-Agent", "origin"]).unwrap();
+        assert_eq!(
+            policy.forward,
+            Forward::Allowlist(vec!["user-agent".to_string(), "origin".to_string()])
+        );
+        assert!(!policy.forward_client_headers());
+    }
+
+    #[test]
+    fn rejects_headers_that_cant_be_forwarded() {
+        assert!(parse(true, &["cookie"]).is_err());
+    }
+
+    #[test]
+    fn extra_headers_replace_headers_of_the_same_name() {
+        let policy = HeaderPolicy::parse(
+            true,
+            &[],
+            &headers(r#"{"X-Proxy-Tenant": "brand-a", "User-Agent": "edge-proxy"}"#),
+            &headers(r#"{"x-proxy-auth": "Bearer secret"}"#),
+        )
+        .unwrap();
+
+        let mut request_headers = vec![
+            ("content-type".to_string(), "application/json".to_string()),
+            ("user-agent".to_string(), "Mozilla/5.0".to_string()),
+        ];
+        policy.add_extra_headers(&mut request_headers);
+        assert_eq!(
+            request_headers,
+            vec![
+                ("content-type".to_string(), "application/json".to_string()),
+                ("user-agent".to_string(), "edge-proxy".to_string()),
+                ("x-proxy-tenant".to_string(), "brand-a".to_string()),
+                ("x-proxy-auth".to_string(), "Bearer secret".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn extra_headers_cannot_replace_the_headers_of_the_component() {
+        for name in ["Authorization", "content-type"] {
+            let extra = headers(&format!(r#"{{"{name}": "Bearer proxy"}}"#));
+            let error = HeaderPolicy::parse(true, &[], &extra, &BTreeMap::new()).unwrap_err();
+            assert!(error.to_string().contains("amplitude_headers"), "{error}");
+            let error = HeaderPolicy::parse(true, &[], &BTreeMap::new(), &extra).unwrap_err();
+            assert!(
+                error.to_string().contains("amplitude_secret_headers"),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        for json in [
+            r#"{"x proxy": "1"}"#,
+            r#"{"x-proxy": "1\r\nx-injected: 1"}"#,
+        ] {
+            let policy = HeaderPolicy::parse(true, &[], &headers(json), &BTreeMap::new());
+            assert!(policy.is_err());
+        }
+    }
+}
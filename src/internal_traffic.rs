@@ -0,0 +1,159 @@
+//! Detection of internal and QA traffic, from the IP address of the client
+//! and from lists of user ids and device ids.
+
+use anyhow::anyhow;
+use std::net::IpAddr;
+
+use crate::exports::edgee::components::data_collection::{Data, Event};
+
+/// What happens to internal events.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum InternalTrafficPolicy {
+    /// Events are not sent.
+    Drop,
+    /// Events are sent to another project, with this API key.
+    Sandbox(String),
+    /// Events are sent with the `is_internal` event property.
+    Flag,
+}
+
+/// Internal IP ranges and ids, with the policy applied to their events.
+#[derive(Debug)]
+pub(crate) struct InternalTraffic {
+    pub(crate) policy: InternalTrafficPolicy,
+    networks: Vec<Network>,
+    user_ids: Vec<String>,
+    device_ids: Vec<String>,
+}
+
+/// An IP range in CIDR notation.
+#[derive(Debug, PartialEq)]
+struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl InternalTraffic {
+    /// Returns `None` when no internal IP range or id is configured.
+    pub(crate) fn parse(
+        ips: &[String],
+        user_ids: &[String],
+        device_ids: &[String],
+        policy: InternalTrafficPolicy,
+    ) -> anyhow::Result<Option<Self>> {
+        let networks = ips
+            .iter()
+            .map(|network| Network::parse(network))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if networks.is_empty() && user_ids.is_empty() && device_ids.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(Self {
+            policy,
+            networks,
+            user_ids: user_ids.to_vec(),
+            device_ids: device_ids.to_vec(),
+        }))
+    }
+
+    /// Whether the event comes from an internal IP, user or device.
+    pub(crate) fn matches(&self, edgee_event: &Event, device_id: Option<&str>) -> bool {
+        // a user event also carries the user id being identified
+        let identified_user_id = match &edgee_event.data {
+            Data::User(data) => data.user_id.as_str(),
+            _ => "",
+        };
+        let is_internal_user =
+            |user_id: &str| !user_id.is_empty() && self.user_ids.iter().any(|id| id == user_id);
+        if is_internal_user(&edgee_event.context.user.user_id)
+            || is_internal_user(identified_user_id)
+        {
+            return true;
+        }
+        if device_id.is_some_and(|device_id| self.device_ids.iter().any(|id| id == device_id)) {
+            return true;
+        }
+
+        match edgee_event.context.client.ip.trim().parse::<IpAddr>() {
+            Ok(ip) => self.networks.iter().any(|network| network.contains(ip)),
+            Err(_) => false,
+        }
+    }
+}
+
+impl Network {
+    fn parse(value: &str) -> anyhow::Result<Self> {
+        let invalid = || {
+            anyhow!(
+                "Invalid amplitude_internal_ips {value:?}, expected an IP address or a CIDR range"
+            )
+        };
+
+        let (addr, prefix_len) = match value.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (value, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>().map_err(|_| invalid())?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(invalid());
+        }
+
+        Ok(Self { addr, prefix_len })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_len))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(network: &str, ip: &str) -> bool {
+        Network::parse(network)
+            .unwrap()
+            .contains(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn matches_ipv4_and_ipv6_ranges() {
+        assert!(contains("10.0.0.0/8", "10.20.30.40"));
+        assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+        assert!(contains("203.0.113.42", "203.0.113.42"));
+        assert!(!contains("203.0.113.42", "203.0.113.43"));
+        assert!(contains("0.0.0.0/0", "198.51.100.1"));
+        assert!(contains("2001:db8::/32", "2001:db8:1234::1"));
+        assert!(!contains("2001:db8::/32", "2001:db9::1"));
+        assert!(contains("192.0.2.0/24", "::ffff:192.0.2.10"));
+        assert!(!contains("2001:db8::/32", "192.0.2.10"));
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        assert!(Network::parse("10.0.0.0/33").is_err());
+        assert!(Network::parse("2001:db8::/129").is_err());
+        assert!(Network::parse("10.0.0").is_err());
+        assert!(Network::parse("10.0.0.0/abc").is_err());
+    }
+}
@@ -1,77 +1,86 @@
 mod amplitude_payload;
+mod autocapture;
+mod bots;
+mod device_id;
+mod error;
+#[cfg(test)]
+mod fixtures;
+mod headers;
+mod internal_traffic;
+mod mapping;
+#[cfg(test)]
+mod mock_amplitude;
+#[cfg(test)]
+mod proptests;
+mod routing;
+mod settings;
+#[cfg(test)]
+mod snapshots;
+mod static_properties;
+mod trace;
 
 use crate::amplitude_payload::parse_value;
 use amplitude_payload::AmplitudeEvent;
 use amplitude_payload::AmplitudePayload;
+use amplitude_payload::UserIdChange;
+use amplitude_payload::UserPropertiesMerge;
+use bots::BotPolicy;
+use error::{Error, ErrorMode};
 use exports::edgee::components::data_collection::Data;
 use exports::edgee::components::data_collection::Dict;
 use exports::edgee::components::data_collection::EdgeeRequest;
 use exports::edgee::components::data_collection::Event;
 use exports::edgee::components::data_collection::Guest;
 use exports::edgee::components::data_collection::HttpMethod;
+use exports::edgee::components::data_collection::UserData;
+use internal_traffic::InternalTrafficPolicy;
 use std::vec;
+use trace::DebugOutput;
 
 wit_bindgen::generate!({world: "data-collection", path: ".edgee/wit", generate_all});
 
 export!(AmplitudeComponent);
 
 const DEFAULT_ENDPOINT: &str = "https://api2.amplitude.com/2/httpapi";
+const DEFAULT_USERMAP_ENDPOINT: &str = "https://api.amplitude.com/usermap";
+const DEFAULT_DELETION_ENDPOINT: &str = "https://amplitude.com/api/2/deletions/users";
+
+/// User event property requesting the deletion of the user.
+const DELETE_PROPERTY: &str = "$delete";
+/// User event property holding the Amplitude id of the user to delete.
+const AMPLITUDE_ID_PROPERTY: &str = "$amplitude_id";
 
 struct AmplitudeComponent;
 
 impl Guest for AmplitudeComponent {
     fn page(edgee_event: Event, settings: Dict) -> Result<EdgeeRequest, String> {
-        use serde_json::Value as v;
-
-        if let Data::Page(ref data) = edgee_event.data {
-            let mut amplitude_payload =
-                AmplitudePayload::new(settings).map_err(|e| e.to_string())?;
-
-            // calculate session_id
-            let session_id = edgee_event
-                .context
-                .session
-                .session_id
-                .parse::<u64>()
-                .unwrap()
-                * 1000;
+        Self::page_request(edgee_event, settings).map_err(String::from)
+    }
 
-            // session_end event
-            if edgee_event.context.session.session_start
-                && !edgee_event.context.session.previous_session_id.is_empty()
-                && edgee_event.context.session.session_id
-                    != edgee_event.context.session.previous_session_id
-            {
-                let previous_session_id_int = edgee_event
-                    .context
-                    .session
-                    .previous_session_id
-                    .clone()
-                    .parse::<u64>()
-                    .unwrap();
-                let previous_session_id = previous_session_id_int * 1000;
-                let mut session_end_event =
-                    AmplitudeEvent::new("session_end", &edgee_event, previous_session_id)
-                        .map_err(|e| e.to_string())?;
-                session_end_event.time = edgee_event.timestamp - 2;
+    fn track(edgee_event: Event, settings: Dict) -> Result<EdgeeRequest, String> {
+        Self::track_request(edgee_event, settings).map_err(String::from)
+    }
 
-                amplitude_payload.events.push(session_end_event);
-            }
+    fn user(edgee_event: Event, settings: Dict) -> Result<EdgeeRequest, String> {
+        Self::user_request(edgee_event, settings).map_err(String::from)
+    }
+}
 
-            // session_start event
-            if edgee_event.context.session.session_start {
-                let mut session_start_event =
-                    AmplitudeEvent::new("session_start", &edgee_event, session_id)
-                        .map_err(|e| e.to_string())?;
-                session_start_event.time = edgee_event.timestamp - 1;
+impl AmplitudeComponent {
+    fn page_request(edgee_event: Event, settings: Dict) -> Result<EdgeeRequest, Error> {
+        use serde_json::Value as v;
 
-                amplitude_payload.events.push(session_start_event);
-            }
+        if let Data::Page(ref data) = edgee_event.data {
+            let mut amplitude_payload = AmplitudePayload::new(settings, &edgee_event)?;
+            let prepared = prepare(&mut amplitude_payload, &edgee_event)?;
 
             // page_view event
-            let mut event =
-                AmplitudeEvent::new("[Amplitude] Page Viewed", &edgee_event, session_id)
-                    .map_err(|e| e.to_string())?;
+            let mut event = AmplitudeEvent::new(
+                "[Amplitude] Page Viewed",
+                &edgee_event,
+                prepared.session_id,
+                prepared.device_id.as_deref(),
+            );
             event.time = edgee_event.timestamp;
 
             let mut event_props = serde_json::Map::new();
@@ -95,8 +104,8 @@ impl Guest for AmplitudeComponent {
                 v::String(data.url.clone()),
             );
 
-            let parsed_url = url::Url::parse(data.url.clone().as_str()).unwrap();
-            if let Some(page_domain) = parsed_url.domain() {
+            let parsed_url = url::Url::parse(&data.url).ok();
+            if let Some(page_domain) = parsed_url.as_ref().and_then(|url| url.domain()) {
                 event_props.insert(
                     "[Amplitude] Page Domain".to_string(),
                     v::String(page_domain.to_string()),
@@ -159,37 +168,64 @@ impl Guest for AmplitudeComponent {
                 );
             }
 
+            // trace the sources of the page properties, custom ones last as they win
+            let trace = &mut amplitude_payload.trace;
+            for (key, source) in [
+                ("[Amplitude] Page Location", "data.url+data.search"),
+                ("[Amplitude] Page Path", "data.path"),
+                ("[Amplitude] Page Title", "data.title"),
+                ("[Amplitude] Page URL", "data.url"),
+                ("[Amplitude] Page Domain", "data.url"),
+                ("name", "data.name"),
+                ("category", "data.category"),
+                ("keywords", "data.keywords"),
+                ("utm_campaign", "context.campaign.name"),
+                ("utm_source", "context.campaign.source"),
+                ("utm_medium", "context.campaign.medium"),
+                ("utm_term", "context.campaign.term"),
+                ("utm_content", "context.campaign.content"),
+            ] {
+                if event_props.contains_key(key) {
+                    trace.source(&format!("event_properties.{key}"), source);
+                }
+            }
+            for (key, _) in &data.properties {
+                trace.source(
+                    &format!("event_properties.{key}"),
+                    &format!("data.properties.{key}"),
+                );
+            }
+
             event.event_properties = Some(serde_json::to_value(event_props).unwrap());
 
-            amplitude_payload.events.push(event);
+            if amplitude_payload.session_replay {
+                event.link_session_replay();
+            }
 
-            Ok(build_edgee_request(amplitude_payload))
+            finish(amplitude_payload, &edgee_event, prepared, event)
         } else {
-            Err("Missing page data".to_string())
+            Err(Error::MissingData("Missing page data".to_string()))
         }
     }
 
-    fn track(edgee_event: Event, settings: Dict) -> Result<EdgeeRequest, String> {
+    fn track_request(edgee_event: Event, settings: Dict) -> Result<EdgeeRequest, Error> {
+        use serde_json::Value as v;
+
         if let Data::Track(ref data) = edgee_event.data {
             if data.name.is_empty() {
-                return Err("Missing event name".to_string());
+                return Err(Error::MissingData("Missing event name".to_string()));
             }
 
-            let mut amplitude_payload =
-                AmplitudePayload::new(settings).map_err(|e| e.to_string())?;
-
-            // calculate session_id
-            let session_id = edgee_event
-                .context
-                .session
-                .session_id
-                .parse::<u64>()
-                .unwrap()
-                * 1000;
+            let mut amplitude_payload = AmplitudePayload::new(settings, &edgee_event)?;
+            let prepared = prepare(&mut amplitude_payload, &edgee_event)?;
 
-            // create a new event and prepare it
-            let mut event = AmplitudeEvent::new(&data.name, &edgee_event, session_id)
-                .map_err(|e| e.to_string())?;
+            // create a new event
+            let mut event = AmplitudeEvent::new(
+                &data.name,
+                &edgee_event,
+                prepared.session_id,
+                prepared.device_id.as_deref(),
+            );
 
             // set event time
             event.time = edgee_event.timestamp;
@@ -201,42 +237,139 @@ impl Guest for AmplitudeComponent {
                     properties.insert(key.clone(), parse_value(value));
                 }
             }
+
+            amplitude_payload.trace.source("event_type", "data.name");
+
+            // autocapture event
+            let mut autocapture_preset = None;
+            if amplitude_payload.autocapture {
+                if let Some(preset) = autocapture::preset(&data.name) {
+                    autocapture_preset = Some(preset);
+                    amplitude_payload
+                        .trace
+                        .source("event_type", "data.name (autocapture preset)");
+                    event.event_type = String::from(preset.event_type);
+                    properties = properties
+                        .into_iter()
+                        .map(|(key, value)| (preset.property_name(&key).to_string(), value))
+                        .collect();
+
+                    let page = &edgee_event.context.page;
+                    for (key, value, source) in [
+                        ("[Amplitude] Page URL", &page.url, "context.page.url"),
+                        ("[Amplitude] Page Path", &page.path, "context.page.path"),
+                        ("[Amplitude] Page Title", &page.title, "context.page.title"),
+                    ] {
+                        if !value.is_empty() && !properties.contains_key(key) {
+                            properties.insert(key.to_string(), v::String(value.clone()));
+                            amplitude_payload
+                                .trace
+                                .source(&format!("event_properties.{key}"), source);
+                        }
+                    }
+                }
+            }
+
+            // experiment exposure event
+            if let Some(experiment) = &amplitude_payload.experiment {
+                if let Some(exposure) = experiment.exposure(&data.properties) {
+                    properties.retain(|key, _| !experiment.is_experiment_property(key));
+                    properties.insert("flag_key".to_string(), v::String(exposure.flag_key.clone()));
+                    properties.insert("variant".to_string(), v::String(exposure.variant.clone()));
+                    if let Some(experiment_key) = exposure.experiment_key {
+                        properties.insert("experiment_key".to_string(), v::String(experiment_key));
+                    }
+
+                    event.event_type = String::from("$exposure");
+                    let trace = &mut amplitude_payload.trace;
+                    trace.source("event_type", "data.properties (experiment exposure)");
+                    for key in ["flag_key", "variant", "experiment_key"] {
+                        if properties.contains_key(key) {
+                            trace.source(
+                                &format!("event_properties.{key}"),
+                                "data.properties (experiment exposure)",
+                            );
+                        }
+                    }
+                    event.set_user_property(
+                        &format!("[Experiment] {}", exposure.flag_key),
+                        v::String(exposure.variant),
+                    );
+                }
+            }
+
+            // trace the sources of the custom properties, renamed by autocapture
+            for (key, _) in &data.properties {
+                let name =
+                    autocapture_preset.map_or(key.as_str(), |preset| preset.property_name(key));
+                if properties.contains_key(name) {
+                    amplitude_payload.trace.source(
+                        &format!("event_properties.{name}"),
+                        &format!("data.properties.{key}"),
+                    );
+                }
+            }
+
             if !properties.is_empty() {
                 event.event_properties = Some(serde_json::to_value(properties).unwrap());
             }
 
-            // add event to amplitude payload
-            amplitude_payload.events.push(event);
+            if amplitude_payload.session_replay {
+                event.link_session_replay();
+            }
 
-            Ok(build_edgee_request(amplitude_payload))
+            finish(amplitude_payload, &edgee_event, prepared, event)
         } else {
-            Err("Missing track data".to_string())
+            Err(Error::MissingData("Missing track data".to_string()))
         }
     }
 
-    fn user(edgee_event: Event, settings: Dict) -> Result<EdgeeRequest, String> {
+    fn user_request(edgee_event: Event, settings: Dict) -> Result<EdgeeRequest, Error> {
         use serde_json::Value as v;
 
         if let Data::User(ref data) = edgee_event.data {
-            if data.user_id.is_empty() && data.anonymous_id.is_empty() {
-                return Err("user_id or anonymous_id is not set".to_string());
+            // erasure request
+            if user_property(&data.properties, DELETE_PROPERTY)
+                .is_some_and(|value| value.trim().eq_ignore_ascii_case("true"))
+            {
+                let amplitude_payload = AmplitudePayload::new(settings, &edgee_event)?;
+                return build_deletion_request(&amplitude_payload, data);
             }
 
-            let mut amplitude_payload =
-                AmplitudePayload::new(settings).map_err(|e| e.to_string())?;
+            if data.user_id.is_empty() && data.anonymous_id.is_empty() {
+                return Err(Error::MissingData(
+                    "user_id or anonymous_id is not set".to_string(),
+                ));
+            }
 
-            // calculate session_id
-            let session_id = edgee_event
-                .context
-                .session
-                .session_id
-                .parse::<u64>()
-                .unwrap()
-                * 1000;
+            let mut amplitude_payload = AmplitudePayload::new(settings, &edgee_event)?;
+            let prepared = prepare(&mut amplitude_payload, &edgee_event)?;
+
+            // The identify event merges the anonymous history of the device
+            // into the user. A user id already sent to Amplitude can only be
+            // merged with the User Mapping API, which doesn't take the traits
+            // of the event: these are kept and sent with identify instead.
+            let previous_user_id = &edgee_event.context.user.user_id;
+            if amplitude_payload.user_id_change == UserIdChange::Usermap
+                && data.properties.is_empty()
+                && !data.user_id.is_empty()
+                && !previous_user_id.is_empty()
+                && previous_user_id != &data.user_id
+            {
+                return Ok(build_usermap_request(
+                    &amplitude_payload,
+                    previous_user_id,
+                    &data.user_id,
+                ));
+            }
 
-            // create a new event and prepare it
-            let mut event = AmplitudeEvent::new("identify", &edgee_event, session_id)
-                .map_err(|e| e.to_string())?;
+            // create a new event
+            let mut event = AmplitudeEvent::new(
+                "identify",
+                &edgee_event,
+                prepared.session_id,
+                prepared.device_id.as_deref(),
+            );
 
             // set event time
             event.time = edgee_event.timestamp;
@@ -262,40 +395,563 @@ impl Guest for AmplitudeComponent {
                 }
             }
 
-            if !properties.is_empty() {
-                event.user_properties = Some(serde_json::to_value(properties).unwrap());
+            match amplitude_payload.user_properties_merge {
+                UserPropertiesMerge::Explicit => {
+                    event.user_properties = None;
+                    for (key, value) in properties {
+                        event.set_user_property(&key, value);
+                    }
+                }
+                UserPropertiesMerge::Context | UserPropertiesMerge::Both => {
+                    // the traits join the `$set` of the context properties,
+                    // see `AmplitudeEvent::set_user_property`
+                    let prefer_explicit =
+                        amplitude_payload.user_properties_merge == UserPropertiesMerge::Both;
+                    for (key, value) in properties {
+                        if prefer_explicit || event.user_property("$set", &key).is_none() {
+                            event.set_user_property(&key, value);
+                        } else {
+                            amplitude_payload
+                                .trace
+                                .skip(&format!("data.properties.{key}"), "context property wins");
+                        }
+                    }
+                }
             }
 
-            // add event to amplitude payload
-            amplitude_payload.events.push(event);
+            // trace the sources of the user fields, which the context ones
+            // don't replace
+            let trace = &mut amplitude_payload.trace;
+            if !data.user_id.is_empty() {
+                trace.source("user_id", "data.user_id");
+            }
+            if !data.anonymous_id.is_empty() {
+                trace.source("user_properties.$set.anonymous_id", "data.anonymous_id");
+            }
+            for (key, value) in &data.properties {
+                if event.user_property("$set", key) == Some(&parse_value(value)) {
+                    trace.source(
+                        &format!("user_properties.$set.{key}"),
+                        &format!("data.properties.{key}"),
+                    );
+                }
+            }
 
-            Ok(build_edgee_request(amplitude_payload))
+            finish(amplitude_payload, &edgee_event, prepared, event)
         } else {
-            Err("Missing user data".to_string())
+            Err(Error::MissingData("Missing user data".to_string()))
+        }
+    }
+}
+
+/// What the steps shared by every event type resolved, before the handler
+/// builds its event.
+struct Prepared {
+    session_id: u64,
+    device_id: Option<String>,
+    is_internal: bool,
+    is_bot: bool,
+    sample_rate: Option<f64>,
+}
+
+/// Applies the filters to the Edgee event, resolves its session and device,
+/// and adds the session events.
+fn prepare(
+    amplitude_payload: &mut AmplitudePayload,
+    edgee_event: &Event,
+) -> Result<Prepared, Error> {
+    // bots and crawlers
+    let is_bot = apply_bot_filter(amplitude_payload, edgee_event)?;
+
+    // invalid optional fields are fatal in strict mode only
+    if amplitude_payload.error_mode == ErrorMode::Strict {
+        check_fields(edgee_event)?;
+    }
+
+    let session_id = session_id_millis(&edgee_event.context.session.session_id);
+    let device_id = amplitude_payload.device_id_source.resolve(edgee_event);
+
+    // internal and QA traffic
+    let is_internal = apply_internal_traffic(amplitude_payload, edgee_event, device_id.as_deref())?;
+
+    // user-level sampling
+    let sample_rate = apply_sampling(amplitude_payload, edgee_event, device_id.as_deref())?;
+
+    // session_end and session_start events
+    push_session_events(
+        amplitude_payload,
+        edgee_event,
+        session_id,
+        device_id.as_deref(),
+    )?;
+
+    Ok(Prepared {
+        session_id,
+        device_id,
+        is_internal,
+        is_bot,
+        sample_rate,
+    })
+}
+
+/// Adds the event built by the handler to the payload, with the field
+/// mappings, the static properties and the filter flags, and builds the
+/// request.
+fn finish(
+    mut amplitude_payload: AmplitudePayload,
+    edgee_event: &Event,
+    prepared: Prepared,
+    mut event: AmplitudeEvent,
+) -> Result<EdgeeRequest, Error> {
+    use serde_json::Value as v;
+
+    trace_event(&mut amplitude_payload, edgee_event, &event);
+    apply_field_mappings(&mut amplitude_payload, edgee_event, &mut event)?;
+    amplitude_payload.events.push(event);
+    apply_static_properties(&mut amplitude_payload);
+
+    if prepared.is_internal {
+        insert_events_property(&mut amplitude_payload, "is_internal", v::Bool(true));
+    }
+    if prepared.is_bot {
+        insert_events_property(&mut amplitude_payload, "is_bot", v::Bool(true));
+    }
+    if let Some(sample_rate) = prepared.sample_rate {
+        insert_events_property(&mut amplitude_payload, "sample_rate", v::from(sample_rate));
+    }
+
+    Ok(build_edgee_request(amplitude_payload, edgee_event))
+}
+
+/// Adds the synthetic session_end and session_start events when the Edgee
+/// event starts a new session, whatever its type.
+///
+/// They are timed just before the event itself, so they are always ordered
+/// first, and carry an `insert_id` derived from the device and the session
+/// so Amplitude drops duplicates if several events claim the same start.
+fn push_session_events(
+    amplitude_payload: &mut AmplitudePayload,
+    edgee_event: &Event,
+    session_id: u64,
+    device_id: Option<&str>,
+) -> Result<(), Error> {
+    let Some(session_events) = &amplitude_payload.session_events else {
+        return Ok(());
+    };
+    let session = &edgee_event.context.session;
+    if !session.session_start || session_id == 0 {
+        return Ok(());
+    }
+
+    // session_end event
+    let previous_session_id = session_id_millis(&session.previous_session_id);
+    if previous_session_id != 0 && previous_session_id != session_id {
+        // the previous session knows nothing about the new referrer and campaign
+        let mut session_end_event = AmplitudeEvent::without_attribution(
+            &session_events.end,
+            edgee_event,
+            previous_session_id,
+            device_id,
+        );
+        session_end_event.time = previous_session_end_time(edgee_event);
+        session_end_event.insert_id = Some(session_insert_id(
+            device_id,
+            &session_events.end,
+            previous_session_id,
+        ));
+
+        amplitude_payload.events.push(session_end_event);
+        amplitude_payload.trace.synthesize(&session_events.end);
+    }
+
+    // session_start event
+    let mut session_start_event =
+        AmplitudeEvent::new(&session_events.start, edgee_event, session_id, device_id);
+    session_start_event.time = edgee_event.timestamp.saturating_sub(1);
+    session_start_event.insert_id = Some(session_insert_id(
+        device_id,
+        &session_events.start,
+        session_id,
+    ));
+
+    amplitude_payload.events.push(session_start_event);
+    amplitude_payload.trace.synthesize(&session_events.start);
+
+    Ok(())
+}
+
+/// Session id in milliseconds, or 0 when the session id is empty or invalid.
+fn session_id_millis(session_id: &str) -> u64 {
+    parse_session_id(session_id).unwrap_or(0)
+}
+
+/// Parses a session id in seconds into milliseconds.
+fn parse_session_id(session_id: &str) -> Option<u64> {
+    session_id.trim().parse::<u64>().ok()?.checked_mul(1000)
+}
+
+/// Returns an error for the first optional field that can't be parsed. In
+/// lenient mode, these fields are skipped instead.
+fn check_fields(edgee_event: &Event) -> Result<(), Error> {
+    match invalid_fields(edgee_event).first() {
+        Some((field, _, value)) => Err(Error::invalid_field(field, value)),
+        None => Ok(()),
+    }
+}
+
+/// Optional fields of the event that can't be parsed: their name, their path
+/// in the Edgee event and their value.
+fn invalid_fields(edgee_event: &Event) -> Vec<(&'static str, &'static str, &str)> {
+    let mut invalid = vec![];
+
+    let session = &edgee_event.context.session;
+    for (field, path, value) in [
+        (
+            "session_id",
+            "context.session.session_id",
+            &session.session_id,
+        ),
+        (
+            "previous_session_id",
+            "context.session.previous_session_id",
+            &session.previous_session_id,
+        ),
+    ] {
+        if !value.is_empty() && parse_session_id(value).is_none() {
+            invalid.push((field, path, value.as_str()));
+        }
+    }
+
+    let (page_url, page_url_path) = match &edgee_event.data {
+        Data::Page(data) => (&data.url, "data.url"),
+        _ => (&edgee_event.context.page.url, "context.page.url"),
+    };
+    for (field, path, value) in [
+        ("url", page_url_path, page_url),
+        (
+            "referrer",
+            "context.page.referrer",
+            &edgee_event.context.page.referrer,
+        ),
+    ] {
+        if !value.is_empty() && url::Url::parse(value).is_err() {
+            invalid.push((field, path, value.as_str()));
+        }
+    }
+
+    invalid
+}
+
+/// Applies the `amplitude_field_mappings` to the event, after the built-in
+/// mapping so that the configured mappings win.
+fn apply_field_mappings(
+    amplitude_payload: &mut AmplitudePayload,
+    edgee_event: &Event,
+    event: &mut AmplitudeEvent,
+) -> Result<(), Error> {
+    amplitude_payload.field_mappings.apply(
+        event,
+        edgee_event,
+        &mut amplitude_payload.trace,
+        amplitude_payload.error_mode,
+    )
+}
+
+/// Adds the static properties to every event of the payload, including the
+/// session events.
+fn apply_static_properties(amplitude_payload: &mut AmplitudePayload) {
+    if let Some(static_properties) = &amplitude_payload.static_properties {
+        for event in amplitude_payload.events.iter_mut() {
+            static_properties.apply(event, &mut amplitude_payload.trace);
+        }
+    }
+}
+
+/// Records in the trace the sources of the fields common to every event
+/// type, and the optional fields skipped because they can't be parsed. The
+/// sources recorded by the handler are kept.
+fn trace_event(
+    amplitude_payload: &mut AmplitudePayload,
+    edgee_event: &Event,
+    event: &AmplitudeEvent,
+) {
+    let trace = &mut amplitude_payload.trace;
+    event.trace_sources(trace, edgee_event);
+    if event.device_id.is_some() {
+        if let Some(source) = amplitude_payload.device_id_source.source(edgee_event) {
+            trace.fallback_source("device_id", &source);
         }
     }
+    for (_, path, _) in invalid_fields(edgee_event) {
+        trace.skip(path, "invalid value");
+    }
+}
+
+fn session_insert_id(device_id: Option<&str>, event_type: &str, session_id: u64) -> String {
+    format!(
+        "{}-{}-{}",
+        device_id.unwrap_or_default(),
+        session_id,
+        event_type
+    )
+}
+
+/// Time of the session end event: the last time the previous session was seen,
+/// or just before the session start when it is unknown.
+fn previous_session_end_time(edgee_event: &Event) -> i64 {
+    let last_seen = edgee_event.context.session.last_seen;
+    if last_seen > 0 && last_seen < edgee_event.timestamp.saturating_sub(1) {
+        last_seen
+    } else {
+        edgee_event.timestamp.saturating_sub(2)
+    }
 }
 
-fn build_edgee_request(amplitude_payload: AmplitudePayload) -> EdgeeRequest {
-    let headers = vec![(
+fn build_edgee_request(
+    mut amplitude_payload: AmplitudePayload,
+    edgee_event: &Event,
+) -> EdgeeRequest {
+    let mut headers = vec![(
         String::from("content-type"),
         String::from("application/json"),
     )];
+    headers.extend(amplitude_payload.headers.client_headers(edgee_event));
+    amplitude_payload.headers.add_extra_headers(&mut headers);
+
+    // mapping trace, in debug mode only, in the event property when it
+    // doesn't fit in a header
+    let trace_output = amplitude_payload.trace.output;
+    if trace_output != DebugOutput::Disabled {
+        let header = (trace_output == DebugOutput::Header)
+            .then(|| amplitude_payload.trace.to_header())
+            .flatten();
+        if let Some(header) = header {
+            headers.push((String::from(trace::TRACE_HEADER), header));
+        } else {
+            let trace = amplitude_payload.trace.to_value();
+            if let Some(event) = amplitude_payload.events.last_mut() {
+                event.insert_event_property(trace::TRACE_PROPERTY, trace);
+            }
+        }
+    }
 
     EdgeeRequest {
         method: HttpMethod::Post,
         url: amplitude_payload.endpoint.clone(),
         headers,
-        forward_client_headers: true,
+        forward_client_headers: amplitude_payload.headers.forward_client_headers(),
         body: serde_json::to_string(&amplitude_payload).unwrap(),
     }
 }
 
+fn user_property<'a>(properties: &'a Dict, name: &str) -> Option<&'a str> {
+    properties
+        .iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+/// Applies the internal traffic policy when the event comes from an internal
+/// IP, user or device. Returns whether the events must be flagged.
+fn apply_internal_traffic(
+    amplitude_payload: &mut AmplitudePayload,
+    edgee_event: &Event,
+    device_id: Option<&str>,
+) -> Result<bool, Error> {
+    let Some(internal_traffic) = &amplitude_payload.internal_traffic else {
+        return Ok(false);
+    };
+    if !internal_traffic.matches(edgee_event, device_id) {
+        return Ok(false);
+    }
+
+    match internal_traffic.policy.clone() {
+        InternalTrafficPolicy::Drop => Err(Error::Filtered(
+            "Event dropped: internal traffic".to_string(),
+        )),
+        InternalTrafficPolicy::Sandbox(api_key) => {
+            amplitude_payload.api_key = api_key;
+            amplitude_payload
+                .trace
+                .filter("internal traffic: sent to the sandbox project");
+            Ok(false)
+        }
+        InternalTrafficPolicy::Flag => {
+            amplitude_payload.trace.filter("internal traffic: flagged");
+            Ok(true)
+        }
+    }
+}
+
+/// Applies the bot policy when the user agent belongs to a bot. Returns
+/// whether the events must be flagged.
+fn apply_bot_filter(
+    amplitude_payload: &mut AmplitudePayload,
+    edgee_event: &Event,
+) -> Result<bool, Error> {
+    let Some(bot_filter) = &amplitude_payload.bot_filter else {
+        return Ok(false);
+    };
+    if !bot_filter.matches(&edgee_event.context.client.user_agent) {
+        return Ok(false);
+    }
+
+    match bot_filter.policy {
+        BotPolicy::Drop => Err(Error::Filtered("Event dropped: bot traffic".to_string())),
+        BotPolicy::Flag => {
+            amplitude_payload.trace.filter("bot traffic: flagged");
+            Ok(true)
+        }
+    }
+}
+
+/// Keeps or drops the event depending on the sampling decision of its user.
+/// Returns the rate to send as the `sample_rate` event property, if enabled.
+fn apply_sampling(
+    amplitude_payload: &mut AmplitudePayload,
+    edgee_event: &Event,
+    device_id: Option<&str>,
+) -> Result<Option<f64>, Error> {
+    let Some(sampling) = &amplitude_payload.sampling else {
+        return Ok(None);
+    };
+
+    let user_id = match &edgee_event.data {
+        Data::User(data) if !data.user_id.is_empty() => data.user_id.as_str(),
+        _ => edgee_event.context.user.user_id.as_str(),
+    };
+    let id = if sampling.by_user_id && !user_id.is_empty() {
+        Some(user_id)
+    } else {
+        device_id.filter(|device_id| !device_id.is_empty())
+    };
+
+    if id.is_some_and(|id| !sampling.keep(id)) {
+        return Err(Error::Filtered(
+            "Event dropped: user not sampled".to_string(),
+        ));
+    }
+    let sample_rate = sampling.rate_property.then_some(sampling.rate);
+    let decision = format!("sampling: kept at rate {}", sampling.rate);
+    amplitude_payload.trace.filter(&decision);
+    Ok(sample_rate)
+}
+
+/// Adds an event property, such as `is_internal`, to every event of the
+/// payload.
+fn insert_events_property(
+    amplitude_payload: &mut AmplitudePayload,
+    key: &str,
+    value: serde_json::Value,
+) {
+    for event in amplitude_payload.events.iter_mut() {
+        event.insert_event_property(key, value.clone());
+    }
+}
+
+/// Builds a request to the User Privacy API deleting the user of a user
+/// event, identified by its `user_id` and/or its `$amplitude_id` property.
+fn build_deletion_request(
+    amplitude_payload: &AmplitudePayload,
+    data: &UserData,
+) -> Result<EdgeeRequest, Error> {
+    use base64::Engine;
+    use serde_json::Value as v;
+
+    let deletion = &amplitude_payload.deletion;
+    let Some(secret_key) = &deletion.secret_key else {
+        return Err(Error::Settings(
+            "Missing Amplitude secret key, required for deletion requests".to_string(),
+        ));
+    };
+
+    let mut body = serde_json::Map::new();
+    if !data.user_id.is_empty() {
+        body.insert(
+            "user_ids".to_string(),
+            v::Array(vec![v::String(data.user_id.clone())]),
+        );
+    }
+    if let Some(amplitude_id) = user_property(&data.properties, AMPLITUDE_ID_PROPERTY) {
+        let amplitude_id = amplitude_id
+            .trim()
+            .parse::<u64>()
+            .map_err(|_| Error::invalid_field(AMPLITUDE_ID_PROPERTY, amplitude_id))?;
+        body.insert(
+            "amplitude_ids".to_string(),
+            v::Array(vec![v::from(amplitude_id)]),
+        );
+    }
+    if body.is_empty() {
+        return Err(Error::MissingData(format!(
+            "user_id or {AMPLITUDE_ID_PROPERTY} is required to delete a user"
+        )));
+    }
+    body.insert("ignore_invalid_id".to_string(), v::from("True"));
+    if let Some(requester) = &deletion.requester {
+        body.insert("requester".to_string(), v::String(requester.clone()));
+    }
+
+    let credentials = base64::engine::general_purpose::STANDARD
+        .encode(format!("{}:{}", amplitude_payload.api_key, secret_key));
+
+    let mut headers = vec![
+        (
+            String::from("content-type"),
+            String::from("application/json"),
+        ),
+        (
+            String::from("authorization"),
+            format!("Basic {credentials}"),
+        ),
+    ];
+    amplitude_payload.headers.add_extra_headers(&mut headers);
+
+    Ok(EdgeeRequest {
+        method: HttpMethod::Post,
+        url: deletion.endpoint.clone(),
+        headers,
+        forward_client_headers: false,
+        body: serde_json::to_string(&body).unwrap(),
+    })
+}
+
+/// Builds a request to the User Mapping API, so that the history of
+/// `user_id` is merged into `global_user_id`.
+fn build_usermap_request(
+    amplitude_payload: &AmplitudePayload,
+    user_id: &str,
+    global_user_id: &str,
+) -> EdgeeRequest {
+    let mapping = serde_json::json!([{
+        "user_id": user_id,
+        "global_user_id": global_user_id,
+    }]);
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("api_key", &amplitude_payload.api_key)
+        .append_pair("mapping", &mapping.to_string())
+        .finish();
+
+    let mut headers = vec![(
+        String::from("content-type"),
+        String::from("application/x-www-form-urlencoded"),
+    )];
+    amplitude_payload.headers.add_extra_headers(&mut headers);
+
+    EdgeeRequest {
+        method: HttpMethod::Post,
+        url: amplitude_payload.usermap_endpoint.clone(),
+        headers,
+        forward_client_headers: false,
+        body,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::exports::edgee::components::data_collection::{
-        Campaign, Client, Context, EventType, PageData, Session, TrackData, UserData,
+        Campaign, Client, Context, EventType, PageData, Session, TrackData,
     };
     use exports::edgee::components::data_collection::Consent;
     use pretty_assertions::assert_eq;
@@ -481,6 +1137,17 @@ mod tests {
         vec![("amplitude_api_key".to_string(), "abc".to_string())]
     }
 
+    /// `sample_settings` with the given settings added.
+    fn settings_with(settings: &[(&str, &str)]) -> Dict {
+        let mut all = sample_settings();
+        all.extend(
+            settings
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string())),
+        );
+        all
+    }
+
     #[test]
     fn page_with_consent() {
         let event = sample_page_event(
@@ -705,4 +1372,1421 @@ mod tests {
             edgee_request.url
         );
     }
+
+    fn body_json(edgee_request: &EdgeeRequest) -> serde_json::Value {
+        serde_json::from_str(&edgee_request.body).unwrap()
+    }
+
+    fn experiment_track_event(properties: Vec<(&str, &str)>) -> Event {
+        let mut event = sample_track_event(
+            "Experiment Viewed".to_string(),
+            Some(Consent::Granted),
+            "abc".to_string(),
+            "fr".to_string(),
+            false,
+        );
+        if let Data::Track(ref mut data) = event.data {
+            data.properties = properties
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+        }
+        event
+    }
+
+    #[test]
+    fn track_with_experiment_properties_becomes_exposure() {
+        let event = experiment_track_event(vec![
+            ("flag_key", "new-checkout"),
+            ("variant", "treatment"),
+            ("experiment_key", "exp-1"),
+            ("position", "3"),
+        ]);
+        let settings = settings_with(&[("amplitude_experiment_exposures", "true")]);
+
+        let edgee_request = AmplitudeComponent::track(event, settings).unwrap();
+        let body = body_json(&edgee_request);
+        let exposure = &body["events"][0];
+
+        assert_eq!(exposure["event_type"], "$exposure");
+        assert_eq!(
+            exposure["event_properties"],
+            serde_json::json!({
+                "flag_key": "new-checkout",
+                "variant": "treatment",
+                "experiment_key": "exp-1",
+                "position": 3,
+            })
+        );
+        assert_eq!(
+            exposure["user_properties"]["$set"]["[Experiment] new-checkout"],
+            "treatment"
+        );
+    }
+
+    #[test]
+    fn track_with_custom_experiment_property_names() {
+        let event = experiment_track_event(vec![("flag", "new-checkout"), ("arm", "control")]);
+        let settings = settings_with(&[
+            ("amplitude_experiment_exposures", "true"),
+            ("amplitude_experiment_flag_key_property", "flag"),
+            ("amplitude_experiment_variant_property", "arm"),
+        ]);
+
+        let edgee_request = AmplitudeComponent::track(event, settings).unwrap();
+        let body = body_json(&edgee_request);
+        let exposure = &body["events"][0];
+
+        assert_eq!(exposure["event_type"], "$exposure");
+        assert_eq!(
+            exposure["event_properties"],
+            serde_json::json!({ "flag_key": "new-checkout", "variant": "control" })
+        );
+    }
+
+    #[test]
+    fn track_without_variant_is_not_an_exposure() {
+        let event = experiment_track_event(vec![("flag_key", "new-checkout")]);
+        let settings = settings_with(&[("amplitude_experiment_exposures", "true")]);
+
+        let edgee_request = AmplitudeComponent::track(event, settings).unwrap();
+        let body = body_json(&edgee_request);
+
+        assert_eq!(body["events"][0]["event_type"], "Experiment Viewed");
+        assert_eq!(
+            body["events"][0]["event_properties"]["flag_key"],
+            "new-checkout"
+        );
+    }
+
+    #[test]
+    fn track_experiment_properties_ignored_when_disabled() {
+        let event = experiment_track_event(vec![("flag_key", "new-checkout"), ("variant", "on")]);
+        let edgee_request = AmplitudeComponent::track(event, sample_settings()).unwrap();
+        let body = body_json(&edgee_request);
+
+        assert_eq!(body["events"][0]["event_type"], "Experiment Viewed");
+    }
+
+    #[test]
+    fn page_and_track_link_session_replay() {
+        let settings = settings_with(&[("amplitude_session_replay", "true")]);
+
+        let event = sample_page_event(None, "abc".to_string(), "fr".to_string(), true);
+        let body = body_json(&AmplitudeComponent::page(event, settings.clone()).unwrap());
+        let events = body["events"].as_array().unwrap();
+        let page_view = events.last().unwrap();
+        assert_eq!(
+            page_view["event_properties"]["[Amplitude] Session Replay ID"],
+            "abc/123000"
+        );
+        // synthetic session events are not part of the replay
+        assert!(events[..events.len() - 1]
+            .iter()
+            .all(|event| event.get("event_properties").is_none()));
+
+        let event = sample_track_event(
+            "event-name".to_string(),
+            None,
+            "abc".to_string(),
+            "fr".to_string(),
+            false,
+        );
+        let body = body_json(&AmplitudeComponent::track(event, settings).unwrap());
+        assert_eq!(
+            body["events"][0]["event_properties"]["[Amplitude] Session Replay ID"],
+            "abc/123000"
+        );
+    }
+
+    #[test]
+    fn session_replay_id_requires_device_and_session() {
+        let settings = settings_with(&[("amplitude_session_replay", "true")]);
+
+        let mut event = sample_track_event(
+            "event-name".to_string(),
+            None,
+            "".to_string(),
+            "fr".to_string(),
+            false,
+        );
+        event.context.user.edgee_id = "".to_string();
+        let body = body_json(&AmplitudeComponent::track(event, settings).unwrap());
+        assert!(body["events"][0]["event_properties"]
+            .get("[Amplitude] Session Replay ID")
+            .is_none());
+    }
+
+    #[test]
+    fn track_with_autocapture_preset() {
+        let mut event = sample_track_event(
+            "form_submit".to_string(),
+            None,
+            "abc".to_string(),
+            "fr".to_string(),
+            false,
+        );
+        if let Data::Track(ref mut data) = event.data {
+            data.properties = vec![
+                ("form_id".to_string(), "signup".to_string()),
+                ("form_destination".to_string(), "/welcome".to_string()),
+                ("step".to_string(), "2".to_string()),
+            ];
+        }
+        let settings = settings_with(&[("amplitude_autocapture_preset", "true")]);
+
+        let body = body_json(&AmplitudeComponent::track(event, settings).unwrap());
+        let form_submitted = &body["events"][0];
+
+        assert_eq!(form_submitted["event_type"], "[Amplitude] Form Submitted");
+        assert_eq!(
+            form_submitted["event_properties"],
+            serde_json::json!({
+                "[Amplitude] Form ID": "signup",
+                "[Amplitude] Form Destination": "/welcome",
+                "[Amplitude] Page URL": "https://example.com/full-url?test=1",
+                "[Amplitude] Page Path": "/full-path",
+                "[Amplitude] Page Title": "page title",
+                "step": 2,
+            })
+        );
+    }
+
+    #[test]
+    fn track_autocapture_names_kept_when_preset_disabled() {
+        let event = sample_track_event(
+            "click".to_string(),
+            None,
+            "abc".to_string(),
+            "fr".to_string(),
+            false,
+        );
+        let body = body_json(&AmplitudeComponent::track(event, sample_settings()).unwrap());
+
+        assert_eq!(body["events"][0]["event_type"], "click");
+    }
+
+    fn session_event_types(settings: Vec<(String, String)>) -> Vec<(String, i64)> {
+        let event = sample_page_event(None, "abc".to_string(), "fr".to_string(), true);
+        let body = body_json(&AmplitudeComponent::page(event, settings).unwrap());
+        body["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| {
+                (
+                    event["event_type"].as_str().unwrap().to_string(),
+                    event["time"].as_i64().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn page_session_events_naming() {
+        let pairs = |pairs: &[(&str, i64)]| -> Vec<(String, i64)> {
+            pairs
+                .iter()
+                .map(|(name, time)| (name.to_string(), *time))
+                .collect()
+        };
+
+        assert_eq!(
+            session_event_types(sample_settings()),
+            pairs(&[
+                ("session_end", 121),
+                ("session_start", 122),
+                ("[Amplitude] Page Viewed", 123)
+            ])
+        );
+
+        let settings = settings_with(&[("amplitude_session_events", "sdk")]);
+        assert_eq!(
+            session_event_types(settings),
+            pairs(&[
+                ("[Amplitude] End Session", 121),
+                ("[Amplitude] Start Session", 122),
+                ("[Amplitude] Page Viewed", 123)
+            ])
+        );
+
+        let settings = settings_with(&[
+            ("amplitude_session_events", "custom"),
+            ("amplitude_session_start_event_name", "Visit Started"),
+            ("amplitude_session_end_event_name", "Visit Ended"),
+        ]);
+        assert_eq!(
+            session_event_types(settings),
+            pairs(&[
+                ("Visit Ended", 121),
+                ("Visit Started", 122),
+                ("[Amplitude] Page Viewed", 123)
+            ])
+        );
+
+        let settings = settings_with(&[("amplitude_session_events", "disabled")]);
+        assert_eq!(
+            session_event_types(settings),
+            pairs(&[("[Amplitude] Page Viewed", 123)])
+        );
+    }
+
+    #[test]
+    fn invalid_session_events_settings_fail() {
+        let event = sample_page_event(None, "abc".to_string(), "fr".to_string(), true);
+        let settings = settings_with(&[("amplitude_session_events", "custom")]);
+        let result = AmplitudeComponent::page(event.clone(), settings);
+        assert!(result
+            .unwrap_err()
+            .contains("amplitude_session_start_event_name"));
+
+        let settings = settings_with(&[("amplitude_session_events", "always")]);
+        assert!(AmplitudeComponent::page(event, settings).is_err());
+    }
+
+    #[test]
+    fn page_session_end_uses_previous_session() {
+        let mut event = sample_page_event(None, "abc".to_string(), "fr".to_string(), true);
+        event.timestamp = 2000;
+        event.context.session.last_seen = 1500;
+
+        let body = body_json(&AmplitudeComponent::page(event, sample_settings()).unwrap());
+        let session_end = &body["events"][0];
+
+        assert_eq!(session_end["event_type"], "session_end");
+        assert_eq!(session_end["time"], 1500);
+        assert_eq!(session_end["session_id"], 345000);
+        assert!(session_end["user_properties"]["$set"]
+            .get("utm_campaign")
+            .is_none());
+        assert!(session_end["user_properties"].get("$setOnce").is_none());
+        assert_eq!(
+            session_end["user_properties"]["$set"]["anonymous_id"],
+            "456"
+        );
+
+        let session_start = &body["events"][1];
+        assert_eq!(session_start["event_type"], "session_start");
+        assert_eq!(session_start["time"], 1999);
+        assert_eq!(
+            session_start["user_properties"]["$set"]["utm_source"],
+            "random"
+        );
+    }
+
+    #[test]
+    fn page_session_end_time_falls_back_without_last_seen() {
+        let mut event = sample_page_event(None, "abc".to_string(), "fr".to_string(), true);
+        event.timestamp = 2000;
+        event.context.session.last_seen = 0;
+
+        let body = body_json(&AmplitudeComponent::page(event, sample_settings()).unwrap());
+        assert_eq!(body["events"][0]["time"], 1998);
+    }
+
+    #[test]
+    fn track_and_user_emit_session_events() {
+        let track_event = sample_track_event(
+            "event-name".to_string(),
+            None,
+            "abc".to_string(),
+            "fr".to_string(),
+            true,
+        );
+        let user_event = sample_user_event(None, "abc".to_string(), "fr".to_string(), true);
+
+        for request in [
+            AmplitudeComponent::track(track_event, sample_settings()).unwrap(),
+            AmplitudeComponent::user(user_event, sample_settings()).unwrap(),
+        ] {
+            let body = body_json(&request);
+            let events = body["events"].as_array().unwrap();
+            assert_eq!(events.len(), 3);
+            assert_eq!(events[0]["event_type"], "session_end");
+            assert_eq!(events[1]["event_type"], "session_start");
+            assert!(events[0]["time"].as_i64() < events[1]["time"].as_i64());
+            assert!(events[1]["time"].as_i64() < events[2]["time"].as_i64());
+        }
+    }
+
+    #[test]
+    fn session_events_share_insert_id_across_event_types() {
+        let page_event = sample_page_event(None, "abc".to_string(), "fr".to_string(), true);
+        let track_event = sample_track_event(
+            "event-name".to_string(),
+            None,
+            "abc".to_string(),
+            "fr".to_string(),
+            true,
+        );
+
+        let page_body =
+            body_json(&AmplitudeComponent::page(page_event, sample_settings()).unwrap());
+        let track_body =
+            body_json(&AmplitudeComponent::track(track_event, sample_settings()).unwrap());
+
+        assert_eq!(
+            page_body["events"][0]["insert_id"],
+            "abc-345000-session_end"
+        );
+        assert_eq!(
+            page_body["events"][1]["insert_id"],
+            "abc-123000-session_start"
+        );
+        assert_eq!(
+            page_body["events"][0]["insert_id"],
+            track_body["events"][0]["insert_id"]
+        );
+        assert_eq!(
+            page_body["events"][1]["insert_id"],
+            track_body["events"][1]["insert_id"]
+        );
+        assert!(page_body["events"][2].get("insert_id").is_none());
+    }
+
+    fn user_properties_with_merge(merge: Option<&str>) -> serde_json::Value {
+        let mut event = sample_user_event(None, "abc".to_string(), "fr".to_string(), false);
+        event.context.user.properties = vec![
+            ("plan".to_string(), "free".to_string()),
+            ("company".to_string(), "Example".to_string()),
+        ];
+        if let Data::User(ref mut data) = event.data {
+            data.properties = vec![("plan".to_string(), "pro".to_string())];
+        }
+        let settings = match merge {
+            Some(merge) => settings_with(&[("amplitude_user_properties_merge", merge)]),
+            None => sample_settings(),
+        };
+
+        let body = body_json(&AmplitudeComponent::user(event, settings).unwrap());
+        body["events"][0]["user_properties"].clone()
+    }
+
+    #[test]
+    fn user_properties_merge_policies() {
+        let both = user_properties_with_merge(None);
+        assert_eq!(both["$set"]["plan"], "pro");
+        assert_eq!(both["$set"]["company"], "Example");
+        assert_eq!(both["$set"]["utm_campaign"], "random");
+        assert_eq!(
+            both["$setOnce"]["initial_referrer"],
+            "https://example.com/another-page"
+        );
+        assert_eq!(both["$set"]["anonymous_id"], "456");
+        // no plain properties next to the operations
+        assert!(both
+            .as_object()
+            .unwrap()
+            .keys()
+            .all(|key| key.starts_with('$')));
+
+        let context = user_properties_with_merge(Some("context"));
+        assert_eq!(context["$set"]["plan"], "free");
+        assert_eq!(context["$set"]["company"], "Example");
+        assert_eq!(context["$set"]["utm_campaign"], "random");
+        assert!(context
+            .as_object()
+            .unwrap()
+            .keys()
+            .all(|key| key.starts_with('$')));
+
+        let explicit = user_properties_with_merge(Some("explicit"));
+        assert_eq!(
+            explicit,
+            serde_json::json!({ "$set": { "anonymous_id": "456", "plan": "pro" } })
+        );
+    }
+
+    #[test]
+    fn invalid_user_properties_merge_fails() {
+        let event = sample_user_event(None, "abc".to_string(), "fr".to_string(), false);
+        let settings = settings_with(&[("amplitude_user_properties_merge", "newest")]);
+        assert!(AmplitudeComponent::user(event, settings)
+            .unwrap_err()
+            .contains("amplitude_user_properties_merge"));
+    }
+
+    #[test]
+    fn device_id_source_with_fallbacks() {
+        let settings = settings_with(&[(
+            "amplitude_device_id_source",
+            "user_property:amp_device_id,anonymous_id",
+        )]);
+
+        let mut event = sample_track_event(
+            "event-name".to_string(),
+            None,
+            "abc".to_string(),
+            "fr".to_string(),
+            true,
+        );
+        let body = body_json(&AmplitudeComponent::track(event.clone(), settings.clone()).unwrap());
+        // no amp_device_id property, falls back to anonymous_id
+        for event in body["events"].as_array().unwrap() {
+            assert_eq!(event["device_id"], "456");
+        }
+
+        event
+            .context
+            .user
+            .properties
+            .push(("amp_device_id".to_string(), "amp-device".to_string()));
+        let body = body_json(&AmplitudeComponent::track(event, settings).unwrap());
+        for event in body["events"].as_array().unwrap() {
+            assert_eq!(event["device_id"], "amp-device");
+        }
+    }
+
+    #[test]
+    fn device_id_source_applies_to_page_and_user() {
+        let settings =
+            settings_with(&[("amplitude_device_id_source", "hash:anonymous_id+edgee_id")]);
+        let expected = format!("{:016x}", device_id::hash_values(&["456", "abc"]));
+
+        let page_event = sample_page_event(None, "abc".to_string(), "fr".to_string(), false);
+        let body = body_json(&AmplitudeComponent::page(page_event, settings.clone()).unwrap());
+        assert_eq!(body["events"][0]["device_id"], expected.as_str());
+
+        let user_event = sample_user_event(None, "abc".to_string(), "fr".to_string(), false);
+        let body = body_json(&AmplitudeComponent::user(user_event, settings).unwrap());
+        assert_eq!(body["events"][0]["device_id"], expected.as_str());
+    }
+
+    #[test]
+    fn device_id_omitted_when_every_source_is_empty() {
+        let mut event = sample_track_event(
+            "event-name".to_string(),
+            None,
+            "".to_string(),
+            "fr".to_string(),
+            false,
+        );
+        event.context.user.anonymous_id = "".to_string();
+        let settings = settings_with(&[("amplitude_device_id_source", "edgee_id,anonymous_id")]);
+
+        let body = body_json(&AmplitudeComponent::track(event, settings).unwrap());
+        assert!(body["events"][0].get("device_id").is_none());
+        assert_eq!(body["events"][0]["user_id"], "123");
+    }
+
+    const USERMAP: (&str, &str) = ("amplitude_user_id_change", "usermap");
+
+    /// A user event without traits, changing the user id of the context.
+    fn user_id_change_event() -> Event {
+        let mut event = sample_user_event(None, "abc".to_string(), "fr".to_string(), true);
+        event.context.user.user_id = "legacy-42".to_string();
+        if let Data::User(ref mut data) = event.data {
+            data.properties.clear();
+        }
+        event
+    }
+
+    #[test]
+    fn user_event_mapped_with_usermap() {
+        let event = user_id_change_event();
+
+        let request = AmplitudeComponent::user(event, settings_with(&[USERMAP])).unwrap();
+        assert_eq!(request.url, DEFAULT_USERMAP_ENDPOINT);
+        assert_eq!(
+            request.headers,
+            vec![(
+                "content-type".to_string(),
+                "application/x-www-form-urlencoded".to_string()
+            )]
+        );
+
+        let form: std::collections::HashMap<String, String> =
+            url::form_urlencoded::parse(request.body.as_bytes())
+                .into_owned()
+                .collect();
+        assert_eq!(form["api_key"], "abc");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&form["mapping"]).unwrap(),
+            serde_json::json!([{ "user_id": "legacy-42", "global_user_id": "123" }])
+        );
+    }
+
+    #[test]
+    fn usermap_keeps_identify_for_traits() {
+        // the User Mapping API can't set the traits, identify does
+        let mut event = sample_user_event(None, "abc".to_string(), "fr".to_string(), false);
+        event.context.user.user_id = "legacy-42".to_string();
+        let request = AmplitudeComponent::user(event, settings_with(&[USERMAP])).unwrap();
+        assert_eq!(request.url, DEFAULT_ENDPOINT);
+        let body = body_json(&request);
+        assert_eq!(body["events"][0]["event_type"], "identify");
+        assert_eq!(body["events"][0]["user_id"], "123");
+        assert_eq!(body["events"][0]["user_properties"]["$set"]["prop4"], "ok");
+    }
+
+    #[test]
+    fn usermap_keeps_identify_without_a_previous_user_id() {
+        // anonymous events carry no user_id, the identify merges them
+        let mut event = sample_user_event(
+            Some(Consent::Granted),
+            "abc".to_string(),
+            "fr".to_string(),
+            false,
+        );
+        event.context.user.user_id = "".to_string();
+        let request = AmplitudeComponent::user(event, settings_with(&[USERMAP])).unwrap();
+        assert_eq!(request.url, DEFAULT_ENDPOINT);
+        let body = body_json(&request);
+        assert_eq!(body["events"][0]["event_type"], "identify");
+        assert_eq!(body["events"][0]["user_id"], "123");
+        assert_eq!(body["events"][0]["device_id"], "abc");
+
+        // same user id as the previous events, nothing to map
+        let event = sample_user_event(
+            Some(Consent::Granted),
+            "abc".to_string(),
+            "fr".to_string(),
+            false,
+        );
+        let request = AmplitudeComponent::user(event, settings_with(&[USERMAP])).unwrap();
+        assert_eq!(body_json(&request)["events"][0]["event_type"], "identify");
+    }
+
+    #[test]
+    fn usermap_requests_go_through_the_filters() {
+        let mut event = user_id_change_event();
+
+        let settings = settings_with(&[
+            USERMAP,
+            ("amplitude_internal_device_ids", "abc"),
+            ("amplitude_internal_traffic", "drop"),
+        ]);
+        let error = AmplitudeComponent::user(event.clone(), settings).unwrap_err();
+        assert!(error.starts_with("filtered:"), "{error}");
+
+        let settings = settings_with(&[USERMAP, ("amplitude_sample_rate", "0.000001")]);
+        let error = AmplitudeComponent::user(event.clone(), settings).unwrap_err();
+        assert!(error.starts_with("filtered:"), "{error}");
+
+        event.context.session.session_id = "abc".to_string();
+        let settings = settings_with(&[USERMAP, ("amplitude_error_mode", "strict")]);
+        let error = AmplitudeComponent::user(event, settings).unwrap_err();
+        assert!(error.starts_with("invalid_field:"), "{error}");
+    }
+
+    #[test]
+    fn invalid_user_id_change_setting_fails() {
+        let event = sample_user_event(None, "abc".to_string(), "fr".to_string(), false);
+        let settings = settings_with(&[("amplitude_user_id_change", "alias")]);
+
+        let error = AmplitudeComponent::user(event, settings).unwrap_err();
+        assert!(error.contains("amplitude_user_id_change"));
+    }
+
+    fn deletion_event(properties: Vec<(&str, &str)>) -> Event {
+        let mut event = sample_user_event(None, "abc".to_string(), "fr".to_string(), false);
+        if let Data::User(ref mut data) = event.data {
+            data.properties = properties
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+        }
+        event
+    }
+
+    const SECRET_KEY: (&str, &str) = ("amplitude_secret_key", "secret");
+
+    #[test]
+    fn user_event_with_delete_property_requests_deletion() {
+        let event = deletion_event(vec![("$delete", "true"), ("$amplitude_id", "42")]);
+        let settings = settings_with(&[
+            SECRET_KEY,
+            ("amplitude_deletion_requester", "dpo@example.com"),
+        ]);
+
+        let request = AmplitudeComponent::user(event, settings).unwrap();
+        assert_eq!(request.url, DEFAULT_DELETION_ENDPOINT);
+        assert!(!request.forward_client_headers);
+        assert!(request.headers.contains(&(
+            "authorization".to_string(),
+            // base64 of "abc:secret"
+            "Basic YWJjOnNlY3JldA==".to_string()
+        )));
+        assert_eq!(
+            body_json(&request),
+            serde_json::json!({
+                "user_ids": ["123"],
+                "amplitude_ids": [42],
+                "ignore_invalid_id": "True",
+                "requester": "dpo@example.com",
+            })
+        );
+    }
+
+    #[test]
+    fn deletion_requires_secret_key_and_ids() {
+        let event = deletion_event(vec![("$delete", "true")]);
+        let error = AmplitudeComponent::user(event, sample_settings()).unwrap_err();
+        assert!(error.contains("secret key"));
+
+        let mut event = deletion_event(vec![("$delete", "true")]);
+        if let Data::User(ref mut data) = event.data {
+            data.user_id = "".to_string();
+        }
+        let error = AmplitudeComponent::user(event, settings_with(&[SECRET_KEY])).unwrap_err();
+        assert!(error.contains("required to delete a user"));
+
+        let event = deletion_event(vec![("$delete", "true"), ("$amplitude_id", "abc")]);
+        let error = AmplitudeComponent::user(event, settings_with(&[SECRET_KEY])).unwrap_err();
+        assert!(error.contains("Invalid $amplitude_id"));
+    }
+
+    #[test]
+    fn delete_property_other_than_true_is_an_identify() {
+        let event = deletion_event(vec![("$delete", "false")]);
+        let request = AmplitudeComponent::user(event, settings_with(&[SECRET_KEY])).unwrap();
+        assert_eq!(request.url, DEFAULT_ENDPOINT);
+        assert_eq!(body_json(&request)["events"][0]["event_type"], "identify");
+    }
+
+    const ROUTES: (&str, &str) = (
+        "amplitude_routes",
+        r#"[
+            {"host": "shop.example.com", "api_key": "shop-key"},
+            {"host": "*.example.com", "event": "purchase", "consent": "granted", "api_key": "purchase-key", "endpoint": "https://api.eu.amplitude.com/2/httpapi"},
+            {"host": "*.example.eu", "event": "user", "api_key": "eu-key", "secret_key": "eu-secret", "usermap_endpoint": "https://api.eu.amplitude.com/usermap", "deletion_endpoint": "https://analytics.eu.amplitude.com/api/2/deletions/users"}
+        ]"#,
+    );
+
+    #[test]
+    fn routes_events_by_host_event_and_consent() {
+        let mut event = sample_track_event(
+            "purchase".to_string(),
+            Some(Consent::Granted),
+            "abc".to_string(),
+            "fr".to_string(),
+            false,
+        );
+        event.context.page.url = "https://www.example.com/checkout".to_string();
+        let request = AmplitudeComponent::track(event.clone(), settings_with(&[ROUTES])).unwrap();
+        assert_eq!(request.url, "https://api.eu.amplitude.com/2/httpapi");
+        assert_eq!(body_json(&request)["api_key"], "purchase-key");
+
+        // the first matching route wins
+        event.context.page.url = "https://shop.example.com/checkout".to_string();
+        let request = AmplitudeComponent::track(event.clone(), settings_with(&[ROUTES])).unwrap();
+        assert_eq!(request.url, DEFAULT_ENDPOINT);
+        assert_eq!(body_json(&request)["api_key"], "shop-key");
+
+        // no route matches, the default project is used
+        event.context.page.url = "https://www.example.com/checkout".to_string();
+        event.consent = Some(Consent::Denied);
+        let request = AmplitudeComponent::track(event, settings_with(&[ROUTES])).unwrap();
+        assert_eq!(request.url, DEFAULT_ENDPOINT);
+        assert_eq!(body_json(&request)["api_key"], "abc");
+
+        // user mappings and deletions go to the region of the routed project
+        let mut event = user_id_change_event();
+        event.context.page.url = "https://www.example.eu/account".to_string();
+        let request = AmplitudeComponent::user(event, settings_with(&[ROUTES, USERMAP])).unwrap();
+        assert_eq!(request.url, "https://api.eu.amplitude.com/usermap");
+        assert!(request.body.starts_with("api_key=eu-key&"));
+
+        let mut event = deletion_event(vec![("$delete", "true")]);
+        event.context.page.url = "https://www.example.eu/account".to_string();
+        let request = AmplitudeComponent::user(event, settings_with(&[ROUTES])).unwrap();
+        assert_eq!(
+            request.url,
+            "https://analytics.eu.amplitude.com/api/2/deletions/users"
+        );
+        assert!(request.headers.contains(&(
+            "authorization".to_string(),
+            // base64 of "eu-key:eu-secret"
+            "Basic ZXUta2V5OmV1LXNlY3JldA==".to_string()
+        )));
+    }
+
+    #[test]
+    fn invalid_routes_setting_fails() {
+        let event = sample_page_event(None, "abc".to_string(), "fr".to_string(), false);
+        let settings = settings_with(&[("amplitude_routes", "brand-a=key")]);
+
+        let error = AmplitudeComponent::page(event, settings).unwrap_err();
+        assert!(error.contains("amplitude_routes"));
+    }
+
+    const INTERNAL_IPS: (&str, &str) = ("amplitude_internal_ips", "10.0.0.0/8, 2001:db8::/32");
+    const INTERNAL_USER_IDS: (&str, &str) = ("amplitude_internal_user_ids", "qa-1");
+    const INTERNAL_API_KEY: (&str, &str) = ("amplitude_internal_api_key", "sandbox-key");
+
+    fn internal_track_event(ip: &str, user_id: &str) -> Event {
+        let mut event = sample_track_event(
+            "event-name".to_string(),
+            Some(Consent::Granted),
+            "abc".to_string(),
+            "fr".to_string(),
+            true,
+        );
+        event.context.client.ip = ip.to_string();
+        event.context.user.user_id = user_id.to_string();
+        event
+    }
+
+    #[test]
+    fn internal_traffic_is_flagged() {
+        let event = internal_track_event("2001:db8::1", "123");
+        let settings = settings_with(&[
+            INTERNAL_IPS,
+            INTERNAL_USER_IDS,
+            INTERNAL_API_KEY,
+            ("amplitude_internal_traffic", "flag"),
+        ]);
+        let body = body_json(&AmplitudeComponent::track(event, settings).unwrap());
+        for event in body["events"].as_array().unwrap() {
+            assert_eq!(event["event_properties"]["is_internal"], true);
+        }
+
+        let event = internal_track_event("203.0.113.1", "123");
+        let settings = settings_with(&[
+            INTERNAL_IPS,
+            INTERNAL_USER_IDS,
+            INTERNAL_API_KEY,
+            ("amplitude_internal_traffic", "flag"),
+        ]);
+        let body = body_json(&AmplitudeComponent::track(event, settings).unwrap());
+        assert!(body["events"][0]["event_properties"]
+            .get("is_internal")
+            .is_none());
+    }
+
+    #[test]
+    fn internal_user_identified_by_a_user_event_is_flagged() {
+        let mut event = sample_user_event(None, "abc".to_string(), "fr".to_string(), false);
+        event.context.user.user_id = "".to_string();
+        if let Data::User(ref mut data) = event.data {
+            data.user_id = "qa-1".to_string();
+        }
+        let settings = settings_with(&[("amplitude_internal_user_ids", "qa-1")]);
+
+        let edgee_request = AmplitudeComponent::user(event, settings).unwrap();
+        let body = body_json(&edgee_request);
+        assert_eq!(body["events"][0]["event_properties"]["is_internal"], true);
+    }
+
+    #[test]
+    fn internal_traffic_is_dropped_or_sandboxed() {
+        let event = internal_track_event("203.0.113.1", "qa-1");
+        let settings = settings_with(&[
+            INTERNAL_IPS,
+            INTERNAL_USER_IDS,
+            INTERNAL_API_KEY,
+            ("amplitude_internal_traffic", "drop"),
+        ]);
+        let error = AmplitudeComponent::track(event, settings).unwrap_err();
+        assert!(error.contains("internal traffic"));
+
+        let event = internal_page_event();
+        let settings = settings_with(&[
+            INTERNAL_IPS,
+            INTERNAL_USER_IDS,
+            INTERNAL_API_KEY,
+            ("amplitude_internal_traffic", "sandbox"),
+        ]);
+        let body = body_json(&AmplitudeComponent::page(event, settings).unwrap());
+        assert_eq!(body["api_key"], "sandbox-key");
+    }
+
+    fn internal_page_event() -> Event {
+        let mut event = sample_page_event(None, "abc".to_string(), "fr".to_string(), false);
+        event.context.client.ip = "10.1.2.3".to_string();
+        event
+    }
+
+    #[test]
+    fn internal_device_ids_match_the_resolved_device_id() {
+        let settings = settings_with(&[
+            ("amplitude_internal_device_ids", "abc"),
+            ("amplitude_internal_traffic", "drop"),
+        ]);
+
+        let event = sample_user_event(None, "abc".to_string(), "fr".to_string(), false);
+        assert!(AmplitudeComponent::user(event, settings).is_err());
+    }
+
+    const BOT_PATTERNS: (&str, &str) = ("amplitude_bot_patterns", "LoadTester");
+
+    #[test]
+    fn bot_traffic_is_dropped() {
+        let mut event = sample_page_event(None, "abc".to_string(), "fr".to_string(), true);
+        event.context.client.user_agent =
+            "Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)".to_string();
+        let settings = settings_with(&[BOT_PATTERNS, ("amplitude_bot_filtering", "drop")]);
+        let error = AmplitudeComponent::page(event.clone(), settings).unwrap_err();
+        assert!(error.contains("bot traffic"));
+
+        // bot filtering is disabled by default
+        assert!(AmplitudeComponent::page(event, sample_settings()).is_ok());
+    }
+
+    #[test]
+    fn bot_traffic_matching_custom_pattern_is_flagged() {
+        let mut event = sample_track_event(
+            "event-name".to_string(),
+            None,
+            "abc".to_string(),
+            "fr".to_string(),
+            true,
+        );
+        event.context.client.user_agent = "loadtester/3.1".to_string();
+        let settings = settings_with(&[BOT_PATTERNS, ("amplitude_bot_filtering", "flag")]);
+        let body = body_json(&AmplitudeComponent::track(event, settings).unwrap());
+        for event in body["events"].as_array().unwrap() {
+            assert_eq!(event["event_properties"]["is_bot"], true);
+        }
+
+        let event = sample_user_event(None, "abc".to_string(), "fr".to_string(), false);
+        let settings = settings_with(&[BOT_PATTERNS, ("amplitude_bot_filtering", "flag")]);
+        let body = body_json(&AmplitudeComponent::user(event, settings).unwrap());
+        assert!(body["events"][0].get("event_properties").is_none());
+    }
+
+    const SAMPLE_RATE_PROPERTY: (&str, &str) = ("amplitude_sample_rate_property", "true");
+
+    fn sampled_page_event(edgee_id: &str) -> Event {
+        sample_page_event(None, edgee_id.to_string(), "fr".to_string(), false)
+    }
+
+    #[test]
+    fn sampling_is_stable_per_device() {
+        let settings = settings_with(&[
+            SAMPLE_RATE_PROPERTY,
+            ("amplitude_sample_rate", "0.5"),
+            ("amplitude_sample_by", "device_id"),
+        ]);
+        let mut kept = 0;
+        for index in 0..200 {
+            let edgee_id = format!("device-{index}");
+            let first = AmplitudeComponent::page(sampled_page_event(&edgee_id), settings.clone());
+            let second = AmplitudeComponent::page(sampled_page_event(&edgee_id), settings.clone());
+            assert_eq!(first.is_ok(), second.is_ok());
+            if let Ok(request) = first {
+                assert_eq!(
+                    body_json(&request)["events"][0]["event_properties"]["sample_rate"],
+                    0.5
+                );
+                kept += 1;
+            } else {
+                assert!(first.unwrap_err().contains("not sampled"));
+            }
+        }
+        // roughly half of the devices are kept
+        assert!((60..140).contains(&kept), "{kept} devices kept");
+    }
+
+    #[test]
+    fn sampling_rate_holds_on_sequential_ids() {
+        let ids: [fn(u64) -> String; 2] = [
+            |index| format!("user-{index}"),
+            |index| (1_700_000_000 + index).to_string(),
+        ];
+        for id in ids {
+            for rate in [0.01, 0.5] {
+                let sampling = amplitude_payload::Sampling {
+                    rate,
+                    by_user_id: false,
+                    rate_property: false,
+                };
+                let kept = (0..100_000)
+                    .filter(|index| sampling.keep(&id(*index)))
+                    .count();
+                let observed = kept as f64 / 100_000.0;
+                // within 5 standard deviations of the rate
+                let tolerance = 5.0 * (rate * (1.0 - rate) / 100_000.0).sqrt();
+                assert!(
+                    (observed - rate).abs() < tolerance,
+                    "{} kept {observed} at rate {rate}",
+                    id(0)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sampling_by_user_id_ignores_the_device() {
+        let settings = settings_with(&[
+            SAMPLE_RATE_PROPERTY,
+            ("amplitude_sample_rate", "0.5"),
+            ("amplitude_sample_by", "user_id"),
+        ]);
+        let results: Vec<bool> = (0..20)
+            .map(|index| {
+                AmplitudeComponent::page(
+                    sampled_page_event(&format!("device-{index}")),
+                    settings.clone(),
+                )
+                .is_ok()
+            })
+            .collect();
+        assert!(results.iter().all(|kept| *kept == results[0]));
+    }
+
+    #[test]
+    fn invalid_sample_rate_fails() {
+        for rate in ["0", "1.5", "half"] {
+            let error = AmplitudeComponent::page(
+                sampled_page_event("abc"),
+                settings_with(&[("amplitude_sample_rate", rate)]),
+            )
+            .unwrap_err();
+            assert!(error.contains("amplitude_sample_rate"));
+        }
+        let error = AmplitudeComponent::page(
+            sampled_page_event("abc"),
+            settings_with(&[
+                ("amplitude_sample_rate", "0.5"),
+                ("amplitude_sample_by", "session"),
+            ]),
+        )
+        .unwrap_err();
+        assert!(error.contains("amplitude_sample_by"));
+    }
+
+    #[test]
+    fn client_headers_allowlist_is_rebuilt_from_the_context() {
+        let event = sample_page_event(None, "abc".to_string(), "fr".to_string(), false);
+        let settings = settings_with(&[
+            ("amplitude_client_headers_allowlist", "user-agent, origin"),
+            ("amplitude_headers", r#"{"x-proxy-tenant": "brand-a"}"#),
+            (
+                "amplitude_secret_headers",
+                r#"{"x-proxy-auth": "Bearer secret"}"#,
+            ),
+        ]);
+
+        let request = AmplitudeComponent::page(event.clone(), settings).unwrap();
+        assert!(!request.forward_client_headers);
+        assert_eq!(
+            request.headers,
+            vec![
+                ("content-type".to_string(), "application/json".to_string()),
+                ("user-agent".to_string(), event.context.client.user_agent),
+                ("origin".to_string(), "https://example.com".to_string()),
+                ("x-proxy-tenant".to_string(), "brand-a".to_string()),
+                ("x-proxy-auth".to_string(), "Bearer secret".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn client_headers_forwarding_can_be_disabled() {
+        let event = sample_page_event(None, "abc".to_string(), "fr".to_string(), false);
+        let settings = settings_with(&[("amplitude_forward_client_headers", "false")]);
+
+        let request = AmplitudeComponent::page(event, settings).unwrap();
+        assert!(!request.forward_client_headers);
+        assert_eq!(request.headers.len(), 1);
+    }
+
+    #[test]
+    fn extra_headers_are_added_to_usermap_requests() {
+        let event = user_id_change_event();
+        let settings = settings_with(&[
+            USERMAP,
+            (
+                "amplitude_secret_headers",
+                r#"{"x-proxy-auth": "Bearer secret"}"#,
+            ),
+        ]);
+
+        let request = AmplitudeComponent::user(event, settings).unwrap();
+        assert_eq!(request.url, DEFAULT_USERMAP_ENDPOINT);
+        assert!(request
+            .headers
+            .contains(&("x-proxy-auth".to_string(), "Bearer secret".to_string())));
+    }
+
+    fn malformed_page_event() -> Event {
+        let mut event = sample_page_event(
+            Some(Consent::Granted),
+            "abc".to_string(),
+            "fr".to_string(),
+            true,
+        );
+        event.context.page.referrer = "not a referrer".to_string();
+        if let Data::Page(ref mut data) = event.data {
+            data.url = "/relative-url".to_string();
+        }
+        event.context.session.previous_session_id = "unknown".to_string();
+        event
+    }
+
+    #[test]
+    fn malformed_fields_are_skipped_in_lenient_mode() {
+        let request = AmplitudeComponent::page(malformed_page_event(), sample_settings()).unwrap();
+        let body = body_json(&request);
+        let events = body["events"].as_array().unwrap();
+
+        // no session_end for an invalid previous session
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event_type"], "session_start");
+
+        let page_view = &events[1];
+        assert!(page_view["event_properties"]
+            .get("[Amplitude] Page Domain")
+            .is_none());
+        assert_eq!(
+            page_view["user_properties"]["$set"]["referrer"],
+            "not a referrer"
+        );
+        assert!(page_view["user_properties"]["$set"]
+            .get("referring_domain")
+            .is_none());
+    }
+
+    #[test]
+    fn malformed_fields_fail_in_strict_mode() {
+        let settings = settings_with(&[("amplitude_error_mode", "strict")]);
+
+        let error = AmplitudeComponent::page(malformed_page_event(), settings.clone()).unwrap_err();
+        assert_eq!(
+            error,
+            "invalid_field: Invalid previous_session_id \"unknown\""
+        );
+
+        let mut event = malformed_page_event();
+        event.context.session.previous_session_id = "".to_string();
+        let error = AmplitudeComponent::page(event, settings.clone()).unwrap_err();
+        assert_eq!(error, "invalid_field: Invalid url \"/relative-url\"");
+
+        let mut event = sample_track_event(
+            "event-name".to_string(),
+            None,
+            "abc".to_string(),
+            "fr".to_string(),
+            false,
+        );
+        event.context.session.session_id = "abc".to_string();
+        let error = AmplitudeComponent::track(event, settings).unwrap_err();
+        assert!(error.starts_with("invalid_field: Invalid session_id"));
+    }
+
+    #[test]
+    fn invalid_session_id_sends_events_without_session() {
+        let mut event = sample_track_event(
+            "event-name".to_string(),
+            None,
+            "abc".to_string(),
+            "fr".to_string(),
+            true,
+        );
+        event.context.session.session_id = "".to_string();
+
+        let body = body_json(&AmplitudeComponent::track(event, sample_settings()).unwrap());
+        let events = body["events"].as_array().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].get("session_id").is_none());
+    }
+
+    #[test]
+    fn errors_carry_a_stable_code() {
+        let event = sample_page_event(None, "abc".to_string(), "fr".to_string(), false);
+        let error = AmplitudeComponent::page(event.clone(), vec![]).unwrap_err();
+        assert!(error.starts_with("settings: "));
+
+        let settings = settings_with(&[("amplitude_error_mode", "paranoid")]);
+        let error = AmplitudeComponent::page(event.clone(), settings).unwrap_err();
+        assert!(error.starts_with("settings: Invalid amplitude_error_mode"));
+
+        let error = AmplitudeComponent::track(event, sample_settings()).unwrap_err();
+        assert_eq!(error, "missing_data: Missing track data");
+    }
+
+    #[test]
+    fn session_ids_overflowing_milliseconds_are_invalid() {
+        assert_eq!(session_id_millis("1700000000"), 1_700_000_000_000);
+        assert_eq!(session_id_millis("18446744073709551615"), 0);
+        assert!(parse_session_id("18446744073709551615").is_none());
+    }
+
+    fn trace_header(edgee_request: &EdgeeRequest) -> Option<serde_json::Value> {
+        edgee_request
+            .headers
+            .iter()
+            .find(|(name, _)| name == trace::TRACE_HEADER)
+            .map(|(_, value)| serde_json::from_str(value).unwrap())
+    }
+
+    #[test]
+    fn debug_trace_is_disabled_by_default() {
+        let event = sample_page_event(None, "abc".to_string(), "fr".to_string(), true);
+        let edgee_request = AmplitudeComponent::page(event, sample_settings()).unwrap();
+        assert!(trace_header(&edgee_request).is_none());
+        assert!(!edgee_request.body.contains(trace::TRACE_PROPERTY));
+    }
+
+    #[test]
+    fn debug_trace_header_records_the_mapping() {
+        let mut event = sample_page_event(None, "abc".to_string(), "fr".to_string(), true);
+        event.context.page.referrer = "not a url".to_string();
+        let settings = settings_with(&[
+            ("amplitude_debug", "header"),
+            ("amplitude_bot_filtering", "flag"),
+            ("amplitude_bot_patterns", "chrome"),
+        ]);
+
+        let edgee_request = AmplitudeComponent::page(event, settings).unwrap();
+        let trace = trace_header(&edgee_request).unwrap();
+        assert_eq!(trace["fields"]["device_id"], "context.user.edgee_id");
+        assert_eq!(trace["fields"]["session_id"], "context.session.session_id");
+        assert_eq!(
+            trace["fields"]["event_properties.[Amplitude] Page URL"],
+            "data.url"
+        );
+        assert_eq!(
+            trace["fields"]["event_properties.prop1"],
+            "data.properties.prop1"
+        );
+        assert_eq!(
+            trace["skipped"],
+            serde_json::json!([{"field": "context.page.referrer", "reason": "invalid value"}])
+        );
+        assert_eq!(
+            trace["filters"],
+            serde_json::json!(["bot traffic: flagged"])
+        );
+        assert_eq!(
+            trace["synthesized"],
+            serde_json::json!(["session_end", "session_start"])
+        );
+        assert!(!edgee_request.body.contains(trace::TRACE_PROPERTY));
+    }
+
+    #[test]
+    fn debug_trace_property_is_set_on_the_event() {
+        let event = sample_user_event(None, "abc".to_string(), "fr".to_string(), false);
+        let settings = settings_with(&[("amplitude_debug", "property")]);
+
+        let edgee_request = AmplitudeComponent::user(event, settings).unwrap();
+        assert!(trace_header(&edgee_request).is_none());
+        let body = body_json(&edgee_request);
+        let trace = &body["events"][0]["event_properties"][trace::TRACE_PROPERTY];
+        assert_eq!(trace["fields"]["user_id"], "data.user_id");
+        assert_eq!(trace["fields"]["event_type"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn debug_trace_too_long_for_a_header_is_set_on_the_event() {
+        let mut event = sample_user_event(None, "abc".to_string(), "fr".to_string(), false);
+        if let Data::User(ref mut data) = event.data {
+            data.properties = (0..200)
+                .map(|index| (format!("trait_{index}"), "value".to_string()))
+                .collect();
+        }
+        let settings = settings_with(&[("amplitude_debug", "header")]);
+
+        let edgee_request = AmplitudeComponent::user(event, settings).unwrap();
+        assert!(trace_header(&edgee_request).is_none());
+        let body = body_json(&edgee_request);
+        let trace = &body["events"][0]["event_properties"][trace::TRACE_PROPERTY];
+        assert_eq!(trace["fields"]["user_id"], "data.user_id");
+    }
+
+    #[test]
+    fn field_mappings_copy_edgee_fields() {
+        let event = sample_track_event(
+            "event-name".to_string(),
+            None,
+            "abc".to_string(),
+            "fr".to_string(),
+            false,
+        );
+        let settings = settings_with(&[(
+            "amplitude_field_mappings",
+            r#"[
+                {"from": "context.client.screen_width", "to": "event_properties.screen_width"},
+                {"from": "context.campaign.creative_format", "to": "user_properties.$setOnce.initial_format"},
+                {"from": "data.properties.prop2", "to": "price", "event": "event-name"},
+                {"from": "data.properties.prop2", "to": "quantity", "event": "purchase"}
+            ]"#,
+        )]);
+
+        let edgee_request = AmplitudeComponent::track(event, settings).unwrap();
+        let body = body_json(&edgee_request);
+        let event = &body["events"][0];
+        assert_eq!(event["event_properties"]["screen_width"], 1024);
+        assert_eq!(
+            event["user_properties"]["$setOnce"]["initial_format"],
+            "random"
+        );
+        assert_eq!(event["price"], 10.0);
+        assert!(event.get("quantity").is_none());
+    }
+
+    #[test]
+    fn unconvertible_field_mappings_fail_in_strict_mode() {
+        let event = || {
+            sample_track_event(
+                "event-name".to_string(),
+                None,
+                "abc".to_string(),
+                "fr".to_string(),
+                false,
+            )
+        };
+        let mappings = (
+            "amplitude_field_mappings",
+            r#"[{"from": "data.properties.prop1", "to": "price"}]"#,
+        );
+
+        let settings = settings_with(&[mappings, ("amplitude_debug", "header")]);
+        let edgee_request = AmplitudeComponent::track(event(), settings).unwrap();
+        assert!(body_json(&edgee_request)["events"][0]
+            .get("price")
+            .is_none());
+        assert_eq!(
+            trace_header(&edgee_request).unwrap()["skipped"],
+            serde_json::json!([{"field": "data.properties.prop1", "reason": "value doesn't fit price"}])
+        );
+
+        let settings = settings_with(&[mappings, ("amplitude_error_mode", "strict")]);
+        let error = AmplitudeComponent::track(event(), settings).unwrap_err();
+        assert_eq!(
+            error,
+            "invalid_field: Invalid data.properties.prop1 \"value1\""
+        );
+    }
+
+    #[test]
+    fn invalid_field_mappings_setting_fails() {
+        let event = sample_page_event(None, "abc".to_string(), "fr".to_string(), true);
+        let settings = settings_with(&[(
+            "amplitude_field_mappings",
+            r#"[{"from": "context.client.mood", "to": "platform"}]"#,
+        )]);
+        let error = AmplitudeComponent::page(event, settings).unwrap_err();
+        assert!(error.contains("mapping 0 reads unknown field \"context.client.mood\""));
+    }
+
+    const STATIC_EVENT_PROPERTIES: (&str, &str) = (
+        "amplitude_static_event_properties",
+        r#"{"environment": "prod", "prop1": "static"}"#,
+    );
+    const STATIC_USER_PROPERTIES: (&str, &str) = (
+        "amplitude_static_user_properties",
+        r#"{"edge_region": "eu-west"}"#,
+    );
+
+    #[test]
+    fn static_properties_are_added_to_every_event() {
+        let event = sample_page_event(None, "abc".to_string(), "fr".to_string(), true);
+        let edgee_request = AmplitudeComponent::page(
+            event,
+            settings_with(&[
+                STATIC_EVENT_PROPERTIES,
+                STATIC_USER_PROPERTIES,
+                ("amplitude_static_properties_precedence", "event"),
+            ]),
+        )
+        .unwrap();
+        let body = body_json(&edgee_request);
+        let events = body["events"].as_array().unwrap();
+        assert_eq!(events.len(), 3);
+        for event in events {
+            assert_eq!(event["event_properties"]["environment"], "prod");
+            assert_eq!(event["user_properties"]["$set"]["edge_region"], "eu-west");
+        }
+    }
+
+    #[test]
+    fn static_properties_precedence() {
+        let event = || {
+            sample_track_event(
+                "event-name".to_string(),
+                None,
+                "abc".to_string(),
+                "fr".to_string(),
+                false,
+            )
+        };
+        let settings = |precedence| {
+            settings_with(&[
+                STATIC_EVENT_PROPERTIES,
+                STATIC_USER_PROPERTIES,
+                ("amplitude_static_properties_precedence", precedence),
+            ])
+        };
+
+        let edgee_request = AmplitudeComponent::track(event(), settings("event")).unwrap();
+        let body = body_json(&edgee_request);
+        assert_eq!(body["events"][0]["event_properties"]["prop1"], "value1");
+
+        let edgee_request = AmplitudeComponent::track(event(), settings("static")).unwrap();
+        let body = body_json(&edgee_request);
+        assert_eq!(body["events"][0]["event_properties"]["prop1"], "static");
+
+        let error = AmplitudeComponent::track(event(), settings("last")).unwrap_err();
+        assert!(error.starts_with("settings: Invalid amplitude_static_properties_precedence"));
+    }
+
+    #[test]
+    fn client_properties_are_opt_in() {
+        let event = || {
+            let mut event = sample_page_event(None, "abc".to_string(), "fr".to_string(), false);
+            event.context.client.screen_density = 1.1;
+            event
+        };
+
+        let edgee_request = AmplitudeComponent::page(event(), sample_settings()).unwrap();
+        let body = body_json(&edgee_request);
+        assert!(body["events"][0]["event_properties"]
+            .get("screen_width")
+            .is_none());
+
+        let properties = (
+            "amplitude_client_properties",
+            "screen_width, screen_density, user_agent_architecture",
+        );
+        let edgee_request =
+            AmplitudeComponent::page(event(), settings_with(&[properties])).unwrap();
+        let body = body_json(&edgee_request);
+        let event_properties = &body["events"][0]["event_properties"];
+        assert_eq!(event_properties["screen_width"], 1024);
+        assert_eq!(event_properties["screen_density"], 1.1);
+        assert_eq!(event_properties["cpu_architecture"], "x86");
+        assert!(event_properties.get("timezone").is_none());
+
+        let settings = settings_with(&[
+            properties,
+            ("amplitude_client_properties_destination", "user"),
+        ]);
+        let edgee_request = AmplitudeComponent::page(event(), settings).unwrap();
+        let body = body_json(&edgee_request);
+        assert_eq!(
+            body["events"][0]["user_properties"]["$set"]["screen_width"],
+            1024
+        );
+        assert!(body["events"][0]["event_properties"]
+            .get("screen_width")
+            .is_none());
+    }
+
+    #[test]
+    fn unknown_settings_are_traced_outside_of_strict_mode() {
+        let event = || sample_page_event(None, "abc".to_string(), "fr".to_string(), false);
+        let settings = settings_with(&[
+            ("amplitude_sesion_replay", "true"),
+            ("amplitude_debug", "header"),
+        ]);
+
+        let edgee_request = AmplitudeComponent::page(event(), settings).unwrap();
+        assert_eq!(
+            trace_header(&edgee_request).unwrap()["unknown_settings"],
+            serde_json::json!(["amplitude_sesion_replay"])
+        );
+
+        let settings = settings_with(&[
+            ("amplitude_sesion_replay", "true"),
+            ("amplitude_error_mode", "strict"),
+        ]);
+        let error = AmplitudeComponent::page(event(), settings).unwrap_err();
+        assert_eq!(
+            error,
+            "settings: Unknown setting \"amplitude_sesion_replay\""
+        );
+    }
 }
@@ -0,0 +1,555 @@
+//! Declarative field mappings, copying any field of the Edgee event to any
+//! field of the Amplitude event.
+//!
+//! The `amplitude_field_mappings` setting is a JSON array of mappings,
+//! applied in order after the built-in mapping, so they override it:
+//! ```json
+//! [
+//!   { "from": "context.client.screen_width", "to": "event_properties.screen_width" },
+//!   { "from": "data.properties.total", "to": "price", "event": "purchase" },
+//!   { "from": "context.campaign.creative_format", "to": "user_properties.$setOnce.initial_creative_format" }
+//! ]
+//! ```
+//! Sources are the fields of `context.page`, `context.user`, `context.client`,
+//! `context.campaign` and `context.session`, the fields of the event `data`,
+//! and the properties under `context.page.properties.<name>`,
+//! `context.user.properties.<name>` and `data.properties.<name>`.
+//! Destinations are `event_properties.<name>`, `user_properties.$set.<name>`,
+//! `user_properties.$setOnce.<name>` and the top-level fields of the event,
+//! such as `app_version`, `platform`, `language` or `price`.
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::amplitude_payload::{parse_value, AmplitudeEvent, EVENT_FIELDS};
+use crate::error::{Error, ErrorMode};
+use crate::exports::edgee::components::data_collection::{
+    Campaign, Client, Data, Dict, Event, PageData, Session, UserData,
+};
+use crate::routing::event_name;
+use crate::trace::Trace;
+
+const PAGE_FIELDS: &[&str] = &[
+    "name", "category", "keywords", "title", "url", "path", "search", "referrer",
+];
+const USER_FIELDS: &[&str] = &["user_id", "anonymous_id", "edgee_id"];
+const CLIENT_FIELDS: &[&str] = &[
+    "ip",
+    "locale",
+    "timezone",
+    "user_agent",
+    "user_agent_architecture",
+    "user_agent_bitness",
+    "user_agent_version_list",
+    "user_agent_full_version_list",
+    "user_agent_mobile",
+    "user_agent_model",
+    "os_name",
+    "os_version",
+    "screen_width",
+    "screen_height",
+    "screen_density",
+    "continent",
+    "country_code",
+    "country_name",
+    "region",
+    "city",
+];
+const CAMPAIGN_FIELDS: &[&str] = &[
+    "name",
+    "source",
+    "medium",
+    "term",
+    "content",
+    "creative_format",
+    "marketing_tactic",
+];
+const SESSION_FIELDS: &[&str] = &[
+    "session_id",
+    "previous_session_id",
+    "session_count",
+    "session_start",
+    "first_seen",
+    "last_seen",
+];
+
+/// Client fields the built-in mapping ignores, opted in with the
+/// `amplitude_client_properties` setting, with the name of their property.
+pub(crate) const CLIENT_PROPERTIES: &[(&str, &str)] = &[
+    ("timezone", "timezone"),
+    ("screen_width", "screen_width"),
+    ("screen_height", "screen_height"),
+    ("screen_density", "screen_density"),
+    ("continent", "continent"),
+    ("country_name", "country_name"),
+    ("user_agent_architecture", "cpu_architecture"),
+    ("user_agent_bitness", "cpu_bitness"),
+    ("user_agent_mobile", "mobile"),
+    ("user_agent_full_version_list", "browser_full_version_list"),
+];
+
+/// Where the client properties are sent.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) enum PropertyDestination {
+    /// Event properties.
+    #[default]
+    Event,
+    /// User properties, with `$set`.
+    User,
+}
+
+/// A mapping as written in the settings.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct FieldMapping {
+    from: String,
+    to: String,
+    /// Name of the track event, or `page` / `user` for the other events.
+    #[serde(default)]
+    event: Option<String>,
+}
+
+/// Field of the Edgee event a mapping reads.
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    /// A field of a record, such as `context.client.ip`.
+    Field(String),
+    /// A property, such as `data.properties.total`.
+    Property { dict: String, name: String },
+}
+
+/// Field of the Amplitude event a mapping writes.
+#[derive(Debug, Clone, PartialEq)]
+enum Destination {
+    EventProperty(String),
+    SetUserProperty(String),
+    SetOnceUserProperty(String),
+    Field(String),
+}
+
+#[derive(Debug)]
+struct Mapping {
+    from: String,
+    to: String,
+    source: Source,
+    destination: Destination,
+    event: Option<String>,
+}
+
+/// Ordered list of field mappings, empty when the setting is not set.
+#[derive(Debug, Default)]
+pub(crate) struct FieldMappings(Vec<Mapping>);
+
+impl FieldMappings {
+    /// Checks the mappings parsed from the `amplitude_field_mappings` setting.
+    pub(crate) fn new(mappings: Vec<FieldMapping>) -> anyhow::Result<Self> {
+        mappings
+            .into_iter()
+            .enumerate()
+            .map(|(index, mapping)| {
+                let source = Source::parse(mapping.from.trim()).ok_or_else(|| {
+                    anyhow!(
+                        "Invalid amplitude_field_mappings, mapping {index} reads unknown field {:?}",
+                        mapping.from
+                    )
+                })?;
+                let destination = Destination::parse(mapping.to.trim()).ok_or_else(|| {
+                    anyhow!(
+                        "Invalid amplitude_field_mappings, mapping {index} writes unknown field {:?}",
+                        mapping.to
+                    )
+                })?;
+                Ok(Mapping {
+                    from: mapping.from.trim().to_string(),
+                    to: mapping.to.trim().to_string(),
+                    source,
+                    destination,
+                    event: mapping.event,
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map(Self)
+    }
+
+    /// Adds mappings of the client fields opted in with the
+    /// `amplitude_client_properties` setting, before the configured mappings
+    /// so that those can override them. `all` opts in every client field.
+    pub(crate) fn with_client_properties(
+        mut self,
+        fields: &[String],
+        destination: PropertyDestination,
+    ) -> anyhow::Result<Self> {
+        let (prefix, destination): (_, fn(String) -> Destination) = match destination {
+            PropertyDestination::Event => ("event_properties.", Destination::EventProperty),
+            PropertyDestination::User => ("user_properties.$set.", Destination::SetUserProperty),
+        };
+
+        let mut mappings = Vec::new();
+        for (field, name) in CLIENT_PROPERTIES {
+            if fields.iter().any(|item| item == field || item == "all") {
+                let from = format!("context.client.{field}");
+                mappings.push(Mapping {
+                    source: Source::Field(from.clone()),
+                    destination: destination(name.to_string()),
+                    from,
+                    to: format!("{prefix}{name}"),
+                    event: None,
+                });
+            }
+        }
+        if let Some(unknown) = fields.iter().find(|item| {
+            *item != "all" && !CLIENT_PROPERTIES.iter().any(|(field, _)| field == item)
+        }) {
+            let expected = CLIENT_PROPERTIES
+                .iter()
+                .map(|(field, _)| *field)
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(anyhow!(
+                "Invalid amplitude_client_properties {unknown:?}, expected all or {expected}"
+            ));
+        }
+
+        mappings.append(&mut self.0);
+        self.0 = mappings;
+        Ok(self)
+    }
+
+    /// Applies the mappings to the event, skipping the empty sources. A value
+    /// that doesn't fit a top-level field is an error in strict mode only.
+    pub(crate) fn apply(
+        &self,
+        event: &mut AmplitudeEvent,
+        edgee_event: &Event,
+        trace: &mut Trace,
+        error_mode: ErrorMode,
+    ) -> Result<(), Error> {
+        for mapping in &self.0 {
+            if mapping
+                .event
+                .as_ref()
+                .is_some_and(|name| name != event_name(edgee_event))
+            {
+                continue;
+            }
+            let Some(value) = mapping.source.value(edgee_event) else {
+                continue;
+            };
+
+            let written = match &mapping.destination {
+                Destination::EventProperty(name) => {
+                    event.insert_event_property(name, value.clone());
+                    true
+                }
+                Destination::SetUserProperty(name) => {
+                    event.set_user_property(name, value.clone());
+                    true
+                }
+                Destination::SetOnceUserProperty(name) => {
+                    event.set_once_user_property(name, value.clone());
+                    true
+                }
+                Destination::Field(name) => event.set_field(name, &value),
+            };
+            if written {
+                trace.source(&mapping.to, &mapping.from);
+            } else if error_mode == ErrorMode::Strict {
+                let value = match value {
+                    Value::String(value) => value,
+                    other => other.to_string(),
+                };
+                return Err(Error::invalid_field(&mapping.from, &value));
+            } else {
+                trace.skip(&mapping.from, &format!("value doesn't fit {}", mapping.to));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Source {
+    fn parse(path: &str) -> Option<Self> {
+        for dict in ["context.page", "context.user", "data"] {
+            if let Some(name) = path
+                .strip_prefix(dict)
+                .and_then(|rest| rest.strip_prefix(".properties."))
+                .filter(|name| !name.is_empty())
+            {
+                return Some(Source::Property {
+                    dict: dict.to_string(),
+                    name: name.to_string(),
+                });
+            }
+        }
+
+        let (record, field) = path.rsplit_once('.')?;
+        let known = match record {
+            "context.page" => PAGE_FIELDS.contains(&field),
+            "context.user" => USER_FIELDS.contains(&field),
+            "context.client" => CLIENT_FIELDS.contains(&field),
+            "context.campaign" => CAMPAIGN_FIELDS.contains(&field),
+            "context.session" => SESSION_FIELDS.contains(&field),
+            "data" => PAGE_FIELDS.contains(&field) || USER_FIELDS.contains(&field),
+            _ => false,
+        };
+        known.then(|| Source::Field(path.to_string()))
+    }
+
+    /// Value of the field in the event, `None` when it is empty or the event
+    /// type doesn't have it.
+    fn value(&self, edgee_event: &Event) -> Option<Value> {
+        let context = &edgee_event.context;
+        match self {
+            Source::Property { dict, name } => {
+                let properties = match (dict.as_str(), &edgee_event.data) {
+                    ("context.page", _) => &context.page.properties,
+                    ("context.user", _) => &context.user.properties,
+                    (_, Data::Page(data)) => &data.properties,
+                    (_, Data::Track(data)) => &data.properties,
+                    (_, Data::User(data)) => &data.properties,
+                };
+                property(properties, name)
+            }
+            Source::Field(path) => {
+                let (record, field) = path.rsplit_once('.')?;
+                match (record, &edgee_event.data) {
+                    ("context.page", _) => page_field(&context.page, field),
+                    ("context.user", _) => user_field(&context.user, field),
+                    ("context.client", _) => client_field(&context.client, field),
+                    ("context.campaign", _) => campaign_field(&context.campaign, field),
+                    ("context.session", _) => session_field(&context.session, field),
+                    ("data", Data::Page(data)) => page_field(data, field),
+                    ("data", Data::Track(data)) if field == "name" => text(&data.name),
+                    ("data", Data::User(data)) => user_field(data, field),
+                    _ => None,
+                }
+            }
+        }
+    }
+}
+
+impl Destination {
+    fn parse(path: &str) -> Option<Self> {
+        let name = |prefix: &str| {
+            path.strip_prefix(prefix)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+        };
+        if let Some(name) = name("event_properties.") {
+            Some(Destination::EventProperty(name))
+        } else if let Some(name) = name("user_properties.$set.") {
+            Some(Destination::SetUserProperty(name))
+        } else if let Some(name) = name("user_properties.$setOnce.") {
+            Some(Destination::SetOnceUserProperty(name))
+        } else {
+            EVENT_FIELDS
+                .contains(&path)
+                .then(|| Destination::Field(path.to_string()))
+        }
+    }
+}
+
+fn text(value: &str) -> Option<Value> {
+    (!value.is_empty()).then(|| Value::String(value.to_string()))
+}
+
+fn property(properties: &Dict, name: &str) -> Option<Value> {
+    properties
+        .iter()
+        .find(|(key, value)| key == name && !value.is_empty())
+        .map(|(_, value)| parse_value(value))
+}
+
+fn page_field(page: &PageData, field: &str) -> Option<Value> {
+    match field {
+        "name" => text(&page.name),
+        "category" => text(&page.category),
+        "keywords" => (!page.keywords.is_empty()).then(|| Value::from(page.keywords.clone())),
+        "title" => text(&page.title),
+        "url" => text(&page.url),
+        "path" => text(&page.path),
+        "search" => text(&page.search),
+        "referrer" => text(&page.referrer),
+        _ => None,
+    }
+}
+
+fn user_field(user: &UserData, field: &str) -> Option<Value> {
+    match field {
+        "user_id" => text(&user.user_id),
+        "anonymous_id" => text(&user.anonymous_id),
+        "edgee_id" => text(&user.edgee_id),
+        _ => None,
+    }
+}
+
+fn client_field(client: &Client, field: &str) -> Option<Value> {
+    match field {
+        "ip" => text(&client.ip),
+        "locale" => text(&client.locale),
+        "timezone" => text(&client.timezone),
+        "user_agent" => text(&client.user_agent),
+        "user_agent_architecture" => text(&client.user_agent_architecture),
+        "user_agent_bitness" => text(&client.user_agent_bitness),
+        "user_agent_version_list" => text(&client.user_agent_version_list),
+        "user_agent_full_version_list" => text(&client.user_agent_full_version_list),
+        // client hint sent as 0 or 1, with or without its `?` prefix
+        "user_agent_mobile" => match client.user_agent_mobile.trim_start_matches('?') {
+            "1" => Some(Value::Bool(true)),
+            "0" => Some(Value::Bool(false)),
+            _ => text(&client.user_agent_mobile),
+        },
+        "user_agent_model" => text(&client.user_agent_model),
+        "os_name" => text(&client.os_name),
+        "os_version" => text(&client.os_version),
+        "screen_width" => (client.screen_width > 0).then(|| Value::from(client.screen_width)),
+        "screen_height" => (client.screen_height > 0).then(|| Value::from(client.screen_height)),
+        // through the shortest f32 representation, `f64::from` would turn
+        // 1.1 into 1.100000023841858
+        "screen_density" => (client.screen_density > 0.0)
+            .then(|| client.screen_density.to_string().parse::<f64>().ok())
+            .flatten()
+            .and_then(serde_json::Number::from_f64)
+            .map(Value::Number),
+        "continent" => text(&client.continent),
+        "country_code" => text(&client.country_code),
+        "country_name" => text(&client.country_name),
+        "region" => text(&client.region),
+        "city" => text(&client.city),
+        _ => None,
+    }
+}
+
+fn campaign_field(campaign: &Campaign, field: &str) -> Option<Value> {
+    match field {
+        "name" => text(&campaign.name),
+        "source" => text(&campaign.source),
+        "medium" => text(&campaign.medium),
+        "term" => text(&campaign.term),
+        "content" => text(&campaign.content),
+        "creative_format" => text(&campaign.creative_format),
+        "marketing_tactic" => text(&campaign.marketing_tactic),
+        _ => None,
+    }
+}
+
+fn session_field(session: &Session, field: &str) -> Option<Value> {
+    match field {
+        "session_id" => text(&session.session_id),
+        "previous_session_id" => text(&session.previous_session_id),
+        "session_count" => Some(Value::from(session.session_count)),
+        "session_start" => Some(Value::from(session.session_start)),
+        "first_seen" => (session.first_seen > 0).then(|| Value::from(session.first_seen)),
+        "last_seen" => (session.last_seen > 0).then(|| Value::from(session.last_seen)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(from: &str, to: &str) -> FieldMapping {
+        FieldMapping {
+            from: from.to_string(),
+            to: to.to_string(),
+            event: None,
+        }
+    }
+
+    #[test]
+    fn parses_sources_and_destinations() {
+        assert_eq!(
+            Source::parse("data.properties.total"),
+            Some(Source::Property {
+                dict: "data".to_string(),
+                name: "total".to_string()
+            })
+        );
+        assert_eq!(
+            Source::parse("context.client.screen_width"),
+            Some(Source::Field("context.client.screen_width".to_string()))
+        );
+        assert_eq!(
+            Destination::parse("user_properties.$setOnce.initial_format"),
+            Some(Destination::SetOnceUserProperty(
+                "initial_format".to_string()
+            ))
+        );
+        assert_eq!(
+            Destination::parse("app_version"),
+            Some(Destination::Field("app_version".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        for (from, to) in [
+            ("context.client.screen", "event_properties.screen"),
+            ("context.page.properties.", "event_properties.x"),
+            ("properties.total", "price"),
+            ("data.name", "event_properties."),
+            ("data.name", "user_properties.plan"),
+            ("data.name", "user_id"),
+            ("data.name", "time"),
+        ] {
+            assert!(
+                FieldMappings::new(vec![mapping(from, to)]).is_err(),
+                "{from} -> {to}"
+            );
+        }
+    }
+
+    #[test]
+    fn every_declared_field_is_readable() {
+        for (record, fields) in [
+            ("context.page", PAGE_FIELDS),
+            ("context.user", USER_FIELDS),
+            ("context.client", CLIENT_FIELDS),
+            ("context.campaign", CAMPAIGN_FIELDS),
+            ("context.session", SESSION_FIELDS),
+        ] {
+            for field in fields {
+                let path = format!("{record}.{field}");
+                assert_eq!(Source::parse(&path), Some(Source::Field(path.clone())));
+            }
+        }
+        for field in EVENT_FIELDS {
+            let mut event = AmplitudeEvent::default();
+            assert!(event.set_field(field, &Value::from(1)), "{field}");
+        }
+    }
+
+    #[test]
+    fn client_properties_come_before_configured_mappings() {
+        let mappings = FieldMappings::new(vec![mapping("data.name", "event_properties.timezone")])
+            .unwrap()
+            .with_client_properties(&["timezone".to_string()], PropertyDestination::User)
+            .unwrap();
+        let targets: Vec<_> = mappings
+            .0
+            .iter()
+            .map(|mapping| mapping.to.as_str())
+            .collect();
+        assert_eq!(
+            targets,
+            ["user_properties.$set.timezone", "event_properties.timezone"]
+        );
+
+        for (field, _) in CLIENT_PROPERTIES {
+            assert!(CLIENT_FIELDS.contains(field), "{field}");
+        }
+        let all = FieldMappings::default()
+            .with_client_properties(&["all".to_string()], PropertyDestination::Event)
+            .unwrap();
+        assert_eq!(all.0.len(), CLIENT_PROPERTIES.len());
+
+        let error = FieldMappings::default()
+            .with_client_properties(&["ip".to_string()], PropertyDestination::Event)
+            .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Invalid amplitude_client_properties \"ip\", expected all or timezone"));
+    }
+}
@@ -0,0 +1,784 @@
+//! In-process imitation of Amplitude's HTTP V2 API, used to check offline
+//! that the requests built by the component would be accepted.
+//!
+//! The server listens on a random local port and answers like the real
+//! endpoint: `200` with `events_ingested`, `400` with the missing and invalid
+//! fields per event index, `413` when the payload is too large and `429` when
+//! a device or user goes over the events-per-second threshold.
+//!
+//! Requests to `/usermap` are checked against the User Mapping API instead,
+//! and requests to `/api/2/deletions/users` against the User Privacy API.
+
+use crate::exports::edgee::components::data_collection::{EdgeeRequest, HttpMethod};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Amplitude rejects payloads larger than 1MB.
+pub(crate) const MAX_PAYLOAD_SIZE: usize = 1024 * 1024;
+/// Amplitude rejects requests with more than 2000 events.
+pub(crate) const MAX_EVENTS_PER_REQUEST: usize = 2000;
+/// Default `min_id_length` applied by Amplitude when the request has no options.
+const DEFAULT_MIN_ID_LENGTH: usize = 5;
+/// Events per second allowed for a single device or user.
+const DEFAULT_EPS_THRESHOLD: usize = 30;
+
+const USER_PROPERTY_OPERATIONS: [&str; 10] = [
+    "$set",
+    "$setOnce",
+    "$add",
+    "$append",
+    "$prepend",
+    "$unset",
+    "$preInsert",
+    "$postInsert",
+    "$remove",
+    "$clearAll",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum FieldType {
+    String,
+    Integer,
+    Number,
+    Boolean,
+    Object,
+}
+
+impl FieldType {
+    fn matches(self, value: &Value) -> bool {
+        match self {
+            FieldType::String => value.is_string(),
+            FieldType::Integer => value.is_i64() || value.is_u64(),
+            FieldType::Number => value.is_number(),
+            FieldType::Boolean => value.is_boolean(),
+            FieldType::Object => value.is_object(),
+        }
+    }
+}
+
+/// Expected type of every event field documented by the HTTP V2 API.
+const EVENT_FIELDS: [(&str, FieldType); 39] = [
+    ("user_id", FieldType::String),
+    ("device_id", FieldType::String),
+    ("event_type", FieldType::String),
+    ("time", FieldType::Integer),
+    ("event_properties", FieldType::Object),
+    ("user_properties", FieldType::Object),
+    ("groups", FieldType::Object),
+    ("group_properties", FieldType::Object),
+    ("$skip_user_properties_sync", FieldType::Boolean),
+    ("app_version", FieldType::String),
+    ("platform", FieldType::String),
+    ("os_name", FieldType::String),
+    ("os_version", FieldType::String),
+    ("device_brand", FieldType::String),
+    ("device_manufacturer", FieldType::String),
+    ("device_model", FieldType::String),
+    ("carrier", FieldType::String),
+    ("country", FieldType::String),
+    ("region", FieldType::String),
+    ("city", FieldType::String),
+    ("dma", FieldType::String),
+    ("language", FieldType::String),
+    ("price", FieldType::Number),
+    ("quantity", FieldType::Integer),
+    ("revenue", FieldType::Number),
+    ("productId", FieldType::String),
+    ("product_id", FieldType::String),
+    ("revenueType", FieldType::String),
+    ("revenue_type", FieldType::String),
+    ("location_lat", FieldType::Number),
+    ("location_lng", FieldType::Number),
+    ("ip", FieldType::String),
+    ("idfa", FieldType::String),
+    ("idfv", FieldType::String),
+    ("adid", FieldType::String),
+    ("android_id", FieldType::String),
+    ("event_id", FieldType::Integer),
+    ("session_id", FieldType::Integer),
+    ("insert_id", FieldType::String),
+];
+
+/// Response returned by the mock server.
+#[derive(Debug)]
+pub(crate) struct MockResponse {
+    pub(crate) status: u16,
+    pub(crate) body: Value,
+}
+
+/// A running mock of the Amplitude HTTP V2 endpoint.
+pub(crate) struct MockAmplitude {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<Value>>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl MockAmplitude {
+    pub(crate) fn start() -> Self {
+        Self::start_with_eps_threshold(DEFAULT_EPS_THRESHOLD)
+    }
+
+    pub(crate) fn start_with_eps_threshold(eps_threshold: usize) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("unable to bind mock server");
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let server_requests = requests.clone();
+        let server_shutdown = shutdown.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if server_shutdown.load(Ordering::SeqCst) {
+                    break;
+                }
+                if let Ok(stream) = stream {
+                    handle_connection(stream, eps_threshold, &server_requests);
+                }
+            }
+        });
+
+        Self {
+            addr,
+            requests,
+            shutdown,
+        }
+    }
+
+    /// URL to use as the `amplitude_endpoint` setting.
+    pub(crate) fn endpoint(&self) -> String {
+        format!("http://{}/2/httpapi", self.addr)
+    }
+
+    /// URL to use as the `amplitude_usermap_endpoint` setting.
+    pub(crate) fn usermap_endpoint(&self) -> String {
+        format!("http://{}/usermap", self.addr)
+    }
+
+    /// URL to use as the `amplitude_deletion_endpoint` setting.
+    pub(crate) fn deletion_endpoint(&self) -> String {
+        format!("http://{}/api/2/deletions/users", self.addr)
+    }
+
+    /// Bodies of the requests accepted with a `200`, usermap forms as JSON
+    /// objects.
+    pub(crate) fn ingested(&self) -> Vec<Value> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Sends an Edgee request to the mock server, whatever its URL host is.
+    pub(crate) fn send(&self, request: &EdgeeRequest) -> MockResponse {
+        let path = url::Url::parse(&request.url)
+            .map(|url| url.path().to_string())
+            .unwrap_or_else(|_| String::from("/2/httpapi"));
+        let method = match request.method {
+            HttpMethod::Get => "GET",
+            HttpMethod::Put => "PUT",
+            HttpMethod::Post => "POST",
+            HttpMethod::Delete => "DELETE",
+        };
+
+        let mut raw = format!("{method} {path} HTTP/1.1\r\nHost: {}\r\n", self.addr);
+        for (name, value) in &request.headers {
+            raw.push_str(&format!("{name}: {value}\r\n"));
+        }
+        raw.push_str(&format!(
+            "content-length: {}\r\nconnection: close\r\n\r\n",
+            request.body.len()
+        ));
+        raw.push_str(&request.body);
+
+        let mut stream = TcpStream::connect(self.addr).expect("unable to reach mock server");
+        stream.write_all(raw.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(0);
+
+        MockResponse {
+            status,
+            body: serde_json::from_str(body).unwrap_or(Value::Null),
+        }
+    }
+}
+
+impl Drop for MockAmplitude {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // wake up the accept loop so the thread can exit
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+fn handle_connection(stream: TcpStream, eps_threshold: usize, requests: &Mutex<Vec<Value>>) {
+    let mut reader = BufReader::new(stream);
+
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+        return;
+    }
+
+    let mut content_length = 0;
+    let mut content_type = String::new();
+    let mut authorization = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            match name.trim().to_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse().unwrap_or(0),
+                "content-type" => content_type = value.trim().to_lowercase(),
+                "authorization" => authorization = value.trim().to_string(),
+                _ => {}
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    if reader.read_exact(&mut body).is_err() {
+        return;
+    }
+
+    let path = request_line.split_whitespace().nth(1);
+    let is_usermap = path == Some("/usermap");
+    let is_deletion = path == Some("/api/2/deletions/users");
+    let (status, response) = if !request_line.starts_with("POST ") {
+        (405, json!({ "code": 405, "error": "Method not allowed" }))
+    } else if is_usermap {
+        if content_type.starts_with("application/x-www-form-urlencoded") {
+            map_users(&body)
+        } else {
+            (
+                400,
+                json!({ "code": 400, "error": "Invalid content type, expected application/x-www-form-urlencoded" }),
+            )
+        }
+    } else if is_deletion && !authorization.starts_with("Basic ") {
+        (
+            401,
+            json!({ "code": 401, "error": "Invalid API key or secret key" }),
+        )
+    } else if !content_type.starts_with("application/json") {
+        (
+            400,
+            json!({ "code": 400, "error": "Invalid content type, expected application/json" }),
+        )
+    } else if is_deletion {
+        delete_users(&body)
+    } else {
+        ingest(&body, eps_threshold)
+    };
+
+    if status == 200 {
+        if is_usermap {
+            let form: Map<String, Value> = url::form_urlencoded::parse(&body)
+                .map(|(key, value)| (key.into_owned(), Value::String(value.into_owned())))
+                .collect();
+            requests.lock().unwrap().push(Value::Object(form));
+        } else if let Ok(payload) = serde_json::from_slice(&body) {
+            requests.lock().unwrap().push(payload);
+        }
+    }
+
+    let response = response.to_string();
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        _ => "Error",
+    };
+    let mut stream = reader.into_inner();
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {status} {reason}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{response}",
+        response.len()
+    );
+}
+
+/// Validates a User Mapping API form and returns the status code and
+/// response body Amplitude would answer with.
+pub(crate) fn map_users(body: &[u8]) -> (u16, Value) {
+    let form: BTreeMap<String, String> = url::form_urlencoded::parse(body).into_owned().collect();
+
+    match form.get("api_key") {
+        Some(api_key) if !api_key.is_empty() => {}
+        _ => return missing_field("api_key"),
+    }
+    let mapping = match form
+        .get("mapping")
+        .map(|m| serde_json::from_str::<Value>(m))
+    {
+        Some(Ok(Value::Array(mapping))) if !mapping.is_empty() => mapping,
+        _ => return missing_field("mapping"),
+    };
+
+    for entry in &mapping {
+        let user_id = entry.get("user_id").and_then(Value::as_str);
+        let global_user_id = entry.get("global_user_id").and_then(Value::as_str);
+        let unmap = entry.get("unmap").and_then(Value::as_bool).unwrap_or(false);
+        if user_id.is_none_or(str::is_empty) || (!unmap && global_user_id.is_none_or(str::is_empty))
+        {
+            return (
+                400,
+                json!({ "code": 400, "error": "Invalid mapping", "mapping": entry }),
+            );
+        }
+    }
+
+    (200, json!({ "code": 200, "mappings": mapping.len() }))
+}
+
+/// Validates a User Privacy API deletion and returns the status code and
+/// response body Amplitude would answer with.
+pub(crate) fn delete_users(body: &[u8]) -> (u16, Value) {
+    let payload: Value = match serde_json::from_slice(body) {
+        Ok(payload) => payload,
+        Err(_) => {
+            return (
+                400,
+                json!({ "code": 400, "error": "Invalid JSON request body" }),
+            )
+        }
+    };
+
+    let user_ids = payload.get("user_ids").and_then(Value::as_array);
+    let amplitude_ids = payload.get("amplitude_ids").and_then(Value::as_array);
+    if user_ids.is_some_and(|ids| !ids.iter().all(Value::is_string))
+        || amplitude_ids.is_some_and(|ids| !ids.iter().all(|id| id.is_u64()))
+    {
+        return (
+            400,
+            json!({ "code": 400, "error": "Invalid field type", "invalid_field": "user_ids" }),
+        );
+    }
+
+    let users = user_ids.map_or(0, Vec::len) + amplitude_ids.map_or(0, Vec::len);
+    if users == 0 {
+        return missing_field("user_ids");
+    }
+
+    (200, json!({ "code": 200, "users": users }))
+}
+
+/// Validates an HTTP V2 payload and returns the status code and response body
+/// Amplitude would answer with.
+pub(crate) fn ingest(body: &[u8], eps_threshold: usize) -> (u16, Value) {
+    if body.len() > MAX_PAYLOAD_SIZE {
+        return (413, json!({ "code": 413, "error": "Payload too big" }));
+    }
+
+    let payload: Value = match serde_json::from_slice(body) {
+        Ok(payload) => payload,
+        Err(_) => {
+            return (
+                400,
+                json!({ "code": 400, "error": "Invalid JSON request body" }),
+            )
+        }
+    };
+
+    match payload.get("api_key") {
+        Some(Value::String(api_key)) if !api_key.is_empty() => {}
+        _ => return missing_field("api_key"),
+    }
+    let events = match payload.get("events") {
+        Some(Value::Array(events)) if !events.is_empty() => events,
+        Some(Value::Array(_)) | None => return missing_field("events"),
+        Some(_) => {
+            return (
+                400,
+                json!({ "code": 400, "error": "Invalid field type", "invalid_field": "events" }),
+            )
+        }
+    };
+    if events.len() > MAX_EVENTS_PER_REQUEST {
+        return (413, json!({ "code": 413, "error": "Payload too big" }));
+    }
+
+    let min_id_length = payload
+        .pointer("/options/min_id_length")
+        .and_then(Value::as_u64)
+        .map(|length| length as usize)
+        .unwrap_or(DEFAULT_MIN_ID_LENGTH);
+
+    let mut missing_fields: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    let mut invalid_fields: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    let mut silenced: Vec<usize> = vec![];
+
+    for (index, event) in events.iter().enumerate() {
+        let Some(event) = event.as_object() else {
+            invalid_fields
+                .entry("event".to_string())
+                .or_default()
+                .push(index);
+            continue;
+        };
+        validate_event(
+            index,
+            event,
+            min_id_length,
+            &mut missing_fields,
+            &mut invalid_fields,
+        );
+        if event.get("user_id").is_none() && event.get("device_id").is_none() {
+            silenced.push(index);
+        }
+    }
+
+    if !missing_fields.is_empty() || !invalid_fields.is_empty() {
+        let mut response = json!({
+            "code": 400,
+            "error": "Request missing required field or has invalid fields",
+            "events_with_missing_fields": missing_fields,
+            "events_with_invalid_fields": invalid_fields,
+        });
+        if !silenced.is_empty() {
+            response["silenced_events"] = json!(silenced);
+        }
+        return (400, response);
+    }
+
+    if let Some(response) = throttle(events, eps_threshold) {
+        return (429, response);
+    }
+
+    (
+        200,
+        json!({
+            "code": 200,
+            "events_ingested": events.len(),
+            "payload_size_bytes": body.len(),
+            "server_upload_time": 1_700_000_000_000_i64,
+        }),
+    )
+}
+
+fn missing_field(field: &str) -> (u16, Value) {
+    (
+        400,
+        json!({
+            "code": 400,
+            "error": "Request missing required field",
+            "missing_field": field,
+        }),
+    )
+}
+
+fn validate_event(
+    index: usize,
+    event: &Map<String, Value>,
+    min_id_length: usize,
+    missing_fields: &mut BTreeMap<String, Vec<usize>>,
+    invalid_fields: &mut BTreeMap<String, Vec<usize>>,
+) {
+    let mut invalid = |field: &str| {
+        invalid_fields
+            .entry(field.to_string())
+            .or_default()
+            .push(index)
+    };
+
+    for (field, field_type) in EVENT_FIELDS {
+        if let Some(value) = event.get(field) {
+            if !field_type.matches(value) {
+                invalid(field);
+            }
+        }
+    }
+
+    match event.get("event_type") {
+        Some(Value::String(event_type)) if event_type.is_empty() => invalid("event_type"),
+        Some(_) => {}
+        None => missing_fields
+            .entry("event_type".to_string())
+            .or_default()
+            .push(index),
+    }
+
+    for id in ["user_id", "device_id"] {
+        if let Some(Value::String(value)) = event.get(id) {
+            if value.chars().count() < min_id_length {
+                invalid(id);
+            }
+        }
+    }
+    if event.get("user_id").is_none() && event.get("device_id").is_none() {
+        missing_fields
+            .entry("user_id_or_device_id".to_string())
+            .or_default()
+            .push(index);
+    }
+
+    if let Some(Value::Object(user_properties)) = event.get("user_properties") {
+        let valid_operations = user_properties.iter().all(|(key, value)| {
+            !key.starts_with('$')
+                || (USER_PROPERTY_OPERATIONS.contains(&key.as_str())
+                    && (value.is_object() || key == "$clearAll"))
+        });
+        // plain properties next to operations are silently dropped
+        let operations = user_properties
+            .keys()
+            .filter(|key| key.starts_with('$'))
+            .count();
+        let mixed = operations > 0 && operations < user_properties.len();
+        if !valid_operations || mixed {
+            invalid("user_properties");
+        }
+    }
+}
+
+fn throttle(events: &[Value], eps_threshold: usize) -> Option<Value> {
+    let mut devices: BTreeMap<String, usize> = BTreeMap::new();
+    let mut users: BTreeMap<String, usize> = BTreeMap::new();
+    for event in events {
+        if let Some(device_id) = event.get("device_id").and_then(Value::as_str) {
+            *devices.entry(device_id.to_string()).or_default() += 1;
+        }
+        if let Some(user_id) = event.get("user_id").and_then(Value::as_str) {
+            *users.entry(user_id.to_string()).or_default() += 1;
+        }
+    }
+
+    devices.retain(|_, count| *count > eps_threshold);
+    users.retain(|_, count| *count > eps_threshold);
+    if devices.is_empty() && users.is_empty() {
+        return None;
+    }
+
+    let throttled_events: Vec<usize> = events
+        .iter()
+        .enumerate()
+        .filter(|(_, event)| {
+            let device = event.get("device_id").and_then(Value::as_str);
+            let user = event.get("user_id").and_then(Value::as_str);
+            device.is_some_and(|id| devices.contains_key(id))
+                || user.is_some_and(|id| users.contains_key(id))
+        })
+        .map(|(index, _)| index)
+        .collect();
+
+    Some(json!({
+        "code": 429,
+        "error": "Too many requests for some devices and users",
+        "eps_threshold": eps_threshold,
+        "throttled_devices": devices,
+        "throttled_users": users,
+        "throttled_events": throttled_events,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exports::edgee::components::data_collection::Dict;
+    use crate::fixtures::load_case;
+    use crate::snapshots::{call_component, fixture_dirs};
+    use pretty_assertions::assert_eq;
+
+    fn with_endpoint(server: &MockAmplitude, mut settings: Dict) -> Dict {
+        settings
+            .retain(|(key, _)| key != "amplitude_endpoint" && key != "amplitude_usermap_endpoint");
+        settings.push(("amplitude_endpoint".to_string(), server.endpoint()));
+        settings.push((
+            "amplitude_usermap_endpoint".to_string(),
+            server.usermap_endpoint(),
+        ));
+        settings.push((
+            "amplitude_deletion_endpoint".to_string(),
+            server.deletion_endpoint(),
+        ));
+        settings
+    }
+
+    #[test]
+    fn accepts_every_fixture_request() {
+        let server = MockAmplitude::start();
+        let mut sent = 0;
+
+        for dir in fixture_dirs() {
+            let (name, event, settings) = load_case(&dir);
+            // fixtures expecting an error never reach Amplitude
+            let Ok(request) = call_component(event, with_endpoint(&server, settings)) else {
+                continue;
+            };
+            let response = server.send(&request);
+            assert_eq!(response.status, 200, "{name} rejected: {}", response.body);
+            sent += 1;
+            if request.url == server.usermap_endpoint() || request.url == server.deletion_endpoint()
+            {
+                continue;
+            }
+
+            assert!(request.url.starts_with(&server.endpoint()));
+            assert_eq!(
+                response.body["events_ingested"],
+                serde_json::from_str::<Value>(&request.body).unwrap()["events"]
+                    .as_array()
+                    .unwrap()
+                    .len()
+            );
+        }
+
+        assert!(sent > 0);
+        assert_eq!(server.ingested().len(), sent);
+    }
+
+    #[test]
+    fn rejects_incomplete_user_mappings() {
+        let (status, _) = map_users(b"api_key=abc&mapping=%5B%5D");
+        assert_eq!(status, 400);
+
+        let (status, body) =
+            map_users(b"api_key=abc&mapping=%5B%7B%22user_id%22%3A%22anon%22%7D%5D");
+        assert_eq!(status, 400);
+        assert_eq!(body["error"], "Invalid mapping");
+
+        let (status, body) = map_users(
+            b"api_key=abc&mapping=%5B%7B%22user_id%22%3A%22anon%22%2C%22unmap%22%3Atrue%7D%5D",
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body["mappings"], 1);
+    }
+
+    #[test]
+    fn rejects_deletions_without_users() {
+        let (status, body) = delete_users(br#"{"user_ids": [], "requester": "dpo"}"#);
+        assert_eq!(status, 400);
+        assert_eq!(body["missing_field"], "user_ids");
+
+        let (status, _) = delete_users(br#"{"amplitude_ids": ["42"]}"#);
+        assert_eq!(status, 400);
+
+        let (status, body) = delete_users(br#"{"user_ids": ["u"], "amplitude_ids": [42]}"#);
+        assert_eq!(status, 200);
+        assert_eq!(body["users"], 2);
+    }
+
+    #[test]
+    fn reports_events_with_invalid_fields() {
+        let body = json!({
+            "api_key": "test-api-key",
+            "events": [
+                { "event_type": "ok", "device_id": "edgee-789", "time": 1 },
+                { "event_type": "bad", "device_id": "edgee-789", "time": "now", "session_id": -0.5 },
+                { "event_type": "short", "user_id": "1" },
+                { "device_id": "edgee-789" },
+            ],
+        });
+        let (status, response) = ingest(body.to_string().as_bytes(), DEFAULT_EPS_THRESHOLD);
+
+        assert_eq!(status, 400);
+        assert_eq!(
+            response["events_with_invalid_fields"],
+            json!({ "session_id": [1], "time": [1], "user_id": [2] })
+        );
+        assert_eq!(
+            response["events_with_missing_fields"],
+            json!({ "event_type": [3] })
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_user_property_operations() {
+        let body = json!({
+            "api_key": "test-api-key",
+            "events": [{
+                "event_type": "identify",
+                "device_id": "edgee-789",
+                "user_properties": { "$increment": { "count": 1 } },
+            }],
+        });
+        let (status, response) = ingest(body.to_string().as_bytes(), DEFAULT_EPS_THRESHOLD);
+
+        assert_eq!(status, 400);
+        assert_eq!(
+            response["events_with_invalid_fields"],
+            json!({ "user_properties": [0] })
+        );
+    }
+
+    #[test]
+    fn rejects_plain_user_properties_next_to_operations() {
+        let body = json!({
+            "api_key": "test-api-key",
+            "events": [
+                {
+                    "event_type": "identify",
+                    "device_id": "edgee-789",
+                    "user_properties": { "$set": { "plan": "pro" }, "seats": 12 },
+                },
+                {
+                    "event_type": "identify",
+                    "device_id": "edgee-789",
+                    "user_properties": { "plan": "pro", "seats": 12 },
+                },
+            ],
+        });
+        let (status, response) = ingest(body.to_string().as_bytes(), DEFAULT_EPS_THRESHOLD);
+
+        assert_eq!(status, 400);
+        assert_eq!(
+            response["events_with_invalid_fields"],
+            json!({ "user_properties": [0] })
+        );
+    }
+
+    #[test]
+    fn rejects_missing_api_key() {
+        let body = json!({ "events": [{ "event_type": "a", "device_id": "edgee-789" }] });
+        let (status, response) = ingest(body.to_string().as_bytes(), DEFAULT_EPS_THRESHOLD);
+
+        assert_eq!(status, 400);
+        assert_eq!(response["missing_field"], "api_key");
+    }
+
+    #[test]
+    fn rejects_payload_too_big() {
+        let body = json!({
+            "api_key": "test-api-key",
+            "events": [{
+                "event_type": "a",
+                "device_id": "edgee-789",
+                "event_properties": { "blob": "x".repeat(MAX_PAYLOAD_SIZE) },
+            }],
+        });
+        let (status, _) = ingest(body.to_string().as_bytes(), DEFAULT_EPS_THRESHOLD);
+        assert_eq!(status, 413);
+    }
+
+    #[test]
+    fn throttles_devices_over_the_eps_threshold() {
+        let server = MockAmplitude::start_with_eps_threshold(1);
+        let dir = fixture_dirs()
+            .into_iter()
+            .find(|dir| dir.ends_with("page_consent_granted"))
+            .unwrap();
+        let (_, mut event, settings) = load_case(&dir);
+        event.context.session.session_start = true;
+
+        let request = call_component(event, with_endpoint(&server, settings)).unwrap();
+        let response = server.send(&request);
+
+        assert_eq!(response.status, 429);
+        assert_eq!(response.body["eps_threshold"], 1);
+        assert!(response.body["throttled_devices"]
+            .as_object()
+            .unwrap()
+            .contains_key("edgee-789"));
+        assert!(server.ingested().is_empty());
+    }
+}
@@ -0,0 +1,312 @@
+//! Property-based tests of the mapping layer: arbitrary events and settings
+//! must never make the component panic, and every request must be valid.
+
+use crate::amplitude_payload::parse_value;
+use crate::exports::edgee::components::data_collection::{
+    Campaign, Client, Consent, Context, Data, Dict, EdgeeRequest, Event, EventType, Guest,
+    PageData, Session, TrackData, UserData,
+};
+use crate::AmplitudeComponent;
+use proptest::prelude::*;
+use serde_json::Value;
+
+const ERROR_CODES: [&str; 4] = ["settings", "missing_data", "invalid_field", "filtered"];
+
+/// Names of the settings declared in the component manifest.
+fn setting_names() -> Vec<String> {
+    include_str!("../edgee-component.toml")
+        .lines()
+        .filter_map(|line| line.strip_prefix("[component.settings."))
+        .filter_map(|line| line.strip_suffix(']'))
+        .map(String::from)
+        .collect()
+}
+
+/// Strings mixing the usual shapes of event fields with edge cases: empty and
+/// unicode strings, huge numbers, invalid URLs.
+fn text() -> impl Strategy<Value = String> {
+    prop_oneof![
+        Just(String::new()),
+        any::<String>(),
+        "[a-z0-9 _.-]{1,12}",
+        "-?[0-9]{1,25}(\\.[0-9]{0,5})?([eE][+-]?[0-9]{1,3})?",
+        "(https?|ftp|android-app)://[a-z0-9.-]{0,15}(/[^ ]{0,10})?",
+        "/[a-z/]{0,10}",
+        Just("true".to_string()),
+        Just("false".to_string()),
+        Just("NaN".to_string()),
+        Just("inf".to_string()),
+        Just("+1".to_string()),
+        Just("203.0.113.42".to_string()),
+        Just("2001:db8::1".to_string()),
+        Just("Mozilla/5.0 (compatible; Googlebot/2.1)".to_string()),
+    ]
+}
+
+fn dict() -> impl Strategy<Value = Dict> {
+    prop::collection::vec((text(), text()), 0..5)
+}
+
+prop_compose! {
+    fn page_data()(
+        (name, category, title, url, path, search, referrer) in (text(), text(), text(), text(), text(), text(), text()),
+        keywords in prop::collection::vec(text(), 0..3),
+        properties in dict(),
+    ) -> PageData {
+        PageData { name, category, keywords, title, url, path, search, referrer, properties }
+    }
+}
+
+prop_compose! {
+    fn user_data()(
+        user_id in text(),
+        anonymous_id in text(),
+        edgee_id in text(),
+        properties in prop_oneof![
+            dict(),
+            dict().prop_map(|mut properties| {
+                properties.push(("$delete".to_string(), "true".to_string()));
+                properties
+            }),
+        ],
+    ) -> UserData {
+        UserData { user_id, anonymous_id, edgee_id, properties }
+    }
+}
+
+prop_compose! {
+    fn client()(
+        (ip, locale, timezone, user_agent, os_name, os_version) in (text(), text(), text(), text(), text(), text()),
+        (user_agent_model, continent, country_code, country_name, region, city) in (text(), text(), text(), text(), text(), text()),
+        (screen_width, screen_height, screen_density) in (any::<i32>(), any::<i32>(), any::<f32>()),
+        (user_agent_architecture, user_agent_bitness, user_agent_version_list) in (text(), text(), text()),
+        user_agent_full_version_list in text(),
+        user_agent_mobile in prop_oneof![text(), "[?]?[01]"],
+    ) -> Client {
+        Client {
+            ip,
+            locale,
+            timezone,
+            user_agent,
+            user_agent_architecture,
+            user_agent_bitness,
+            user_agent_version_list,
+            user_agent_full_version_list,
+            user_agent_mobile,
+            user_agent_model,
+            os_name,
+            os_version,
+            screen_width,
+            screen_height,
+            screen_density,
+            continent,
+            country_code,
+            country_name,
+            region,
+            city,
+        }
+    }
+}
+
+prop_compose! {
+    fn campaign()(
+        (name, source, medium, term, content) in (text(), text(), text(), text(), text()),
+    ) -> Campaign {
+        Campaign {
+            name,
+            source,
+            medium,
+            term,
+            content,
+            creative_format: String::new(),
+            marketing_tactic: String::new(),
+        }
+    }
+}
+
+prop_compose! {
+    fn session()(
+        session_id in prop_oneof![text(), "[0-9]{1,20}"],
+        previous_session_id in prop_oneof![text(), "[0-9]{1,20}"],
+        session_count in any::<u32>(),
+        session_start in any::<bool>(),
+        first_seen in any::<i64>(),
+        last_seen in any::<i64>(),
+    ) -> Session {
+        Session { session_id, previous_session_id, session_count, session_start, first_seen, last_seen }
+    }
+}
+
+fn data() -> impl Strategy<Value = Data> {
+    prop_oneof![
+        page_data().prop_map(Data::Page),
+        (text(), dict(), prop::collection::vec(dict(), 0..2)).prop_map(
+            |(name, properties, products)| Data::Track(TrackData {
+                name,
+                properties,
+                products,
+            })
+        ),
+        user_data().prop_map(Data::User),
+    ]
+}
+
+prop_compose! {
+    fn event()(
+        timestamp in any::<i64>(),
+        data in data(),
+        context in (page_data(), user_data(), client(), campaign(), session()).prop_map(
+            |(page, user, client, campaign, session)| Context { page, user, client, campaign, session }
+        ),
+        consent in prop_oneof![
+            Just(None),
+            Just(Some(Consent::Pending)),
+            Just(Some(Consent::Granted)),
+            Just(Some(Consent::Denied)),
+        ],
+    ) -> Event {
+        let event_type = match data {
+            Data::Page(_) => EventType::Page,
+            Data::Track(_) => EventType::Track,
+            Data::User(_) => EventType::User,
+        };
+        Event {
+            uuid: String::new(),
+            timestamp,
+            timestamp_millis: timestamp,
+            timestamp_micros: timestamp,
+            event_type,
+            data,
+            context,
+            consent,
+        }
+    }
+}
+
+/// Settings values, valid or not, for any of the manifest settings.
+fn setting_value() -> impl Strategy<Value = String> {
+    prop_oneof![
+        text(),
+        prop::sample::select(vec![
+            "true",
+            "false",
+            "sdk",
+            "custom",
+            "disabled",
+            "explicit",
+            "context",
+            "usermap",
+            "drop",
+            "flag",
+            "sandbox",
+            "strict",
+            "lenient",
+            "user_id",
+            "all",
+            "user",
+            "timezone, screen_density, user_agent_mobile",
+            "0.5",
+            "0.0001",
+            "10.0.0.0/8, 2001:db8::/32",
+            "anonymous_id,edgee_id",
+            "hash:edgee_id+user_property:plan",
+            "user-agent, origin",
+            r#"{"x-proxy": "1"}"#,
+            r#"[{"host": "*.example.com", "api_key": "routed"}]"#,
+        ])
+        .prop_map(String::from),
+    ]
+}
+
+fn settings() -> impl Strategy<Value = Dict> {
+    (
+        prop::option::weighted(0.9, text()),
+        prop::collection::vec(
+            (prop::sample::select(setting_names()), setting_value()),
+            0..6,
+        ),
+    )
+        .prop_map(|(api_key, mut settings)| {
+            if let Some(api_key) = api_key {
+                settings.push(("amplitude_api_key".to_string(), api_key));
+            }
+            settings
+        })
+}
+
+/// A request is valid when its body matches its content type.
+fn assert_valid(result: Result<EdgeeRequest, String>) -> Result<(), TestCaseError> {
+    let request = match result {
+        Ok(request) => request,
+        Err(error) => {
+            prop_assert!(
+                ERROR_CODES
+                    .iter()
+                    .any(|code| error.starts_with(&format!("{code}: "))),
+                "error without code: {error}"
+            );
+            return Ok(());
+        }
+    };
+
+    let content_type = request
+        .headers
+        .iter()
+        .find(|(name, _)| name == "content-type")
+        .map(|(_, value)| value.as_str());
+    match content_type {
+        Some("application/x-www-form-urlencoded") => {
+            let mapping = url::form_urlencoded::parse(request.body.as_bytes())
+                .find(|(key, _)| key == "mapping")
+                .map(|(_, value)| value.into_owned());
+            prop_assert!(mapping.is_some_and(|m| serde_json::from_str::<Value>(&m).is_ok()));
+        }
+        _ => {
+            let body = serde_json::from_str::<Value>(&request.body);
+            prop_assert!(body.is_ok(), "invalid JSON body: {}", request.body);
+            if let Some(events) = body.unwrap().get("events") {
+                prop_assert!(events.as_array().is_some_and(|events| !events.is_empty()));
+            }
+        }
+    }
+    Ok(())
+}
+
+proptest! {
+    #[test]
+    fn component_never_panics(event in event(), settings in settings()) {
+        let result = match event.data {
+            Data::Page(_) => AmplitudeComponent::page(event, settings),
+            Data::Track(_) => AmplitudeComponent::track(event, settings),
+            Data::User(_) => AmplitudeComponent::user(event, settings),
+        };
+        assert_valid(result)?;
+    }
+
+    #[test]
+    fn component_never_panics_on_mismatched_data(event in event(), settings in settings()) {
+        assert_valid(AmplitudeComponent::page(event.clone(), settings.clone()))?;
+        assert_valid(AmplitudeComponent::track(event.clone(), settings.clone()))?;
+        assert_valid(AmplitudeComponent::user(event, settings))?;
+    }
+
+    #[test]
+    fn parse_value_round_trips(value in text()) {
+        match parse_value(&value) {
+            Value::Bool(boolean) => prop_assert_eq!(boolean.to_string(), value),
+            Value::Number(number) => {
+                let expected = value.parse::<f64>().unwrap();
+                let parsed = number.as_f64().unwrap();
+                prop_assert!(
+                    parsed == expected || ((parsed - expected) / expected).abs() < 1e-15,
+                    "{value} parsed as {parsed}"
+                );
+            }
+            Value::String(string) => {
+                prop_assert_eq!(&string, &value);
+                prop_assert!(!matches!(value.as_str(), "true" | "false"));
+            }
+            other => prop_assert!(false, "{value} parsed as {other}"),
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Routing of events to several Amplitude projects.
+//!
+//! The `amplitude_routes` setting is a JSON array of routes. The first route
+//! matching the event gives the API key (and optionally the endpoints and
+//! secret key) of the request, the `amplitude_api_key` setting being used
+//! when no route matches:
+//! ```json
+//! [
+//!   { "host": "*.brand-a.com", "api_key": "..." },
+//!   { "host": "shop.brand-b.com", "event": "purchase", "consent": "granted", "api_key": "..." }
+//! ]
+//! ```
+
+use anyhow::anyhow;
+use serde::Deserialize;
+
+use crate::exports::edgee::components::data_collection::{Consent, Data, Event};
+
+/// A destination project and the events sent to it.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Route {
+    /// Page hostname, `*.example.com` matching every subdomain of example.com.
+    #[serde(default)]
+    host: Option<String>,
+    /// Name of the track event, or `page` / `user` for the other events.
+    #[serde(default)]
+    event: Option<String>,
+    /// Consent of the event: granted, denied or pending.
+    #[serde(default)]
+    consent: Option<String>,
+    pub(crate) api_key: String,
+    #[serde(default)]
+    pub(crate) endpoint: Option<String>,
+    #[serde(default)]
+    pub(crate) secret_key: Option<String>,
+    /// User Mapping API endpoint of the project, for projects outside the US.
+    #[serde(default)]
+    pub(crate) usermap_endpoint: Option<String>,
+    /// User Privacy API endpoint of the project, for projects outside the US.
+    #[serde(default)]
+    pub(crate) deletion_endpoint: Option<String>,
+}
+
+/// Ordered list of routes, empty when the setting is not set.
+#[derive(Debug, Default)]
+pub(crate) struct Routes(Vec<Route>);
+
+impl Routes {
+    /// Checks the routes parsed from the `amplitude_routes` setting.
+    pub(crate) fn new(routes: Vec<Route>) -> anyhow::Result<Self> {
+        for (index, route) in routes.iter().enumerate() {
+            if route.api_key.trim().is_empty() {
+                return Err(anyhow!(
+                    "Invalid amplitude_routes, route {index} has no api_key"
+                ));
+            }
+            if let Some(consent) = &route.consent {
+                if !matches!(consent.as_str(), "granted" | "denied" | "pending") {
+                    return Err(anyhow!(
+                        "Invalid amplitude_routes, route {index} has consent {consent:?}, expected granted, denied or pending"
+                    ));
+                }
+            }
+        }
+        Ok(Self(routes))
+    }
+
+    /// Returns the first route matching the event.
+    pub(crate) fn find(&self, edgee_event: &Event) -> Option<&Route> {
+        self.0.iter().find(|route| route.matches(edgee_event))
+    }
+}
+
+impl Route {
+    fn matches(&self, edgee_event: &Event) -> bool {
+        if let Some(pattern) = &self.host {
+            let host = url::Url::parse(&edgee_event.context.page.url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_lowercase));
+            if !host.is_some_and(|host| host_matches(pattern, &host)) {
+                return false;
+            }
+        }
+
+        if self
+            .event
+            .as_ref()
+            .is_some_and(|event| event != event_name(edgee_event))
+        {
+            return false;
+        }
+
+        if let Some(consent) = &self.consent {
+            let event_consent = match edgee_event.consent {
+                Some(Consent::Granted) => "granted",
+                Some(Consent::Denied) => "denied",
+                Some(Consent::Pending) => "pending",
+                None => return false,
+            };
+            if consent != event_consent {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Name of the event matched by the `event` of routes and mappings: the name
+/// of a track event, or `page` / `user` for the other events.
+pub(crate) fn event_name(edgee_event: &Event) -> &str {
+    match &edgee_event.data {
+        Data::Page(_) => "page",
+        Data::Track(data) => data.name.as_str(),
+        Data::User(_) => "user",
+    }
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.ends_with('.')),
+        None => pattern == "*" || pattern == host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_hostname_patterns() {
+        assert!(host_matches("www.example.com", "www.example.com"));
+        assert!(host_matches("*.example.com", "shop.example.com"));
+        assert!(host_matches("*.Example.com", "a.b.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "notexample.com"));
+        assert!(host_matches("*", "example.org"));
+    }
+
+    fn parse(value: &str) -> anyhow::Result<Routes> {
+        Routes::new(serde_json::from_str(value)?)
+    }
+
+    #[test]
+    fn rejects_invalid_routes() {
+        assert!(parse("[]").unwrap().0.is_empty());
+        assert!(parse("{}").is_err());
+        assert!(parse(r#"[{"host": "a.com"}]"#).is_err());
+        assert!(parse(r#"[{"api_key": " "}]"#).is_err());
+        assert!(parse(r#"[{"api_key": "k", "consent": "yes"}]"#).is_err());
+        assert!(parse(r#"[{"api_key": "k", "hostname": "a.com"}]"#).is_err());
+    }
+}
@@ -0,0 +1,613 @@
+//! Settings of the component, parsed once per call from the dictionary sent
+//! by Edgee.
+//!
+//! [`Settings::parse`] reads every setting with its type (string, boolean,
+//! number, comma-separated list, JSON or one of a set of values) and its
+//! default, and collects the keys it doesn't know, except the `edgee_` ones
+//! set by Edgee itself. The tests check the settings declared in
+//! `edgee-component.toml` against it.
+
+use anyhow::anyhow;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::amplitude_payload::{SessionEventNames, UserIdChange, UserPropertiesMerge};
+use crate::bots::BotPolicy;
+use crate::error::ErrorMode;
+use crate::exports::edgee::components::data_collection::Dict;
+use crate::internal_traffic::InternalTrafficPolicy;
+use crate::mapping::{FieldMapping, PropertyDestination};
+use crate::routing::Route;
+use crate::static_properties::StaticPrecedence;
+use crate::trace::DebugOutput;
+
+/// Prefix of the settings set by Edgee rather than by the user.
+const EDGEE_PREFIX: &str = "edgee_";
+
+#[derive(Debug)]
+pub(crate) struct Settings {
+    pub(crate) api_key: String,
+    pub(crate) endpoint: String,
+    pub(crate) experiment_exposures: bool,
+    pub(crate) experiment_flag_key_property: String,
+    pub(crate) experiment_variant_property: String,
+    pub(crate) experiment_key_property: String,
+    pub(crate) session_replay: bool,
+    pub(crate) autocapture_preset: bool,
+    /// `None` when session events are disabled.
+    pub(crate) session_events: Option<SessionEventNames>,
+    pub(crate) user_properties_merge: UserPropertiesMerge,
+    pub(crate) device_id_source: Vec<String>,
+    pub(crate) user_id_change: UserIdChange,
+    pub(crate) usermap_endpoint: String,
+    pub(crate) secret_key: Option<String>,
+    pub(crate) deletion_endpoint: String,
+    pub(crate) deletion_requester: Option<String>,
+    pub(crate) routes: Vec<Route>,
+    pub(crate) internal_ips: Vec<String>,
+    pub(crate) internal_user_ids: Vec<String>,
+    pub(crate) internal_device_ids: Vec<String>,
+    pub(crate) internal_traffic: InternalTrafficPolicy,
+    /// `None` when bot filtering is disabled.
+    pub(crate) bot_filtering: Option<BotPolicy>,
+    pub(crate) bot_patterns: Vec<String>,
+    /// Between 0 (excluded) and 1.
+    pub(crate) sample_rate: Option<f64>,
+    pub(crate) sample_by_user_id: bool,
+    pub(crate) sample_rate_property: bool,
+    pub(crate) forward_client_headers: bool,
+    pub(crate) client_headers_allowlist: Vec<String>,
+    pub(crate) headers: BTreeMap<String, String>,
+    pub(crate) secret_headers: BTreeMap<String, String>,
+    pub(crate) error_mode: ErrorMode,
+    pub(crate) debug: DebugOutput,
+    pub(crate) field_mappings: Vec<FieldMapping>,
+    pub(crate) client_properties: Vec<String>,
+    pub(crate) client_properties_destination: PropertyDestination,
+    pub(crate) static_event_properties: Map<String, Value>,
+    pub(crate) static_user_properties: Map<String, Value>,
+    pub(crate) static_properties_precedence: StaticPrecedence,
+    /// Keys of the settings the component doesn't know, sorted.
+    pub(crate) unknown: Vec<String>,
+}
+
+impl Settings {
+    /// Unknown settings, such as a leftover or misspelled key, are only
+    /// rejected in strict mode, so that they don't drop every event.
+    pub(crate) fn parse(settings: &Dict) -> anyhow::Result<Self> {
+        let mut reader = Reader::new(settings);
+        let mut parsed = Self::read(&mut reader)?;
+
+        parsed.unknown = reader
+            .values
+            .into_keys()
+            .filter(|key| !key.starts_with(EDGEE_PREFIX))
+            .collect();
+        parsed.unknown.sort();
+        if parsed.error_mode == ErrorMode::Strict && !parsed.unknown.is_empty() {
+            return Err(anyhow!(
+                "Unknown setting {}",
+                parsed
+                    .unknown
+                    .iter()
+                    .map(|key| format!("{key:?}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+
+        Ok(parsed)
+    }
+
+    /// Reads every setting, whatever the values of the others, so that the
+    /// reader knows them all.
+    fn read(reader: &mut Reader) -> anyhow::Result<Self> {
+        let api_key = reader
+            .string("amplitude_api_key")
+            .ok_or_else(|| anyhow!("Missing amplitude_api_key"))?;
+
+        let session_events = reader.choice(
+            "amplitude_session_events",
+            "legacy",
+            &[
+                (
+                    "legacy",
+                    SessionEvents::Names("session_start", "session_end"),
+                ),
+                (
+                    "sdk",
+                    SessionEvents::Names("[Amplitude] Start Session", "[Amplitude] End Session"),
+                ),
+                ("custom", SessionEvents::Custom),
+                ("disabled", SessionEvents::Disabled),
+            ],
+        )?;
+        let session_start_event_name = reader.string("amplitude_session_start_event_name");
+        let session_end_event_name = reader.string("amplitude_session_end_event_name");
+        let session_events = match session_events {
+            SessionEvents::Names(start, end) => Some(SessionEventNames {
+                start: start.to_string(),
+                end: end.to_string(),
+            }),
+            SessionEvents::Custom => {
+                let name = |key: &str, value: Option<String>| {
+                    value.ok_or_else(|| {
+                        anyhow!("Missing {key}, required when amplitude_session_events is custom")
+                    })
+                };
+                Some(SessionEventNames {
+                    start: name(
+                        "amplitude_session_start_event_name",
+                        session_start_event_name,
+                    )?,
+                    end: name("amplitude_session_end_event_name", session_end_event_name)?,
+                })
+            }
+            SessionEvents::Disabled => None,
+        };
+
+        let internal_ips = reader.list("amplitude_internal_ips");
+        let internal_user_ids = reader.list("amplitude_internal_user_ids");
+        let internal_device_ids = reader.list("amplitude_internal_device_ids");
+        let internal_api_key = reader.string("amplitude_internal_api_key");
+        let internal_traffic = match reader.choice(
+            "amplitude_internal_traffic",
+            "flag",
+            &[
+                ("drop", InternalTrafficPolicy::Drop),
+                ("sandbox", InternalTrafficPolicy::Sandbox(String::new())),
+                ("flag", InternalTrafficPolicy::Flag),
+            ],
+        )? {
+            InternalTrafficPolicy::Sandbox(_) => match internal_api_key {
+                Some(api_key) => InternalTrafficPolicy::Sandbox(api_key),
+                // irrelevant without internal traffic to send
+                None if internal_ips.is_empty()
+                    && internal_user_ids.is_empty()
+                    && internal_device_ids.is_empty() =>
+                {
+                    InternalTrafficPolicy::Flag
+                }
+                None => {
+                    return Err(anyhow!(
+                        "Missing amplitude_internal_api_key, required when amplitude_internal_traffic is sandbox"
+                    ))
+                }
+            },
+            policy => policy,
+        };
+
+        let sample_rate = reader.number("amplitude_sample_rate")?;
+        if let Some(rate) = sample_rate.filter(|rate| *rate <= 0.0 || *rate > 1.0) {
+            return Err(anyhow!(
+                "Invalid amplitude_sample_rate {rate:?}, expected a number between 0 (excluded) and 1"
+            ));
+        }
+
+        Ok(Self {
+            api_key,
+            endpoint: reader.string_or("amplitude_endpoint", crate::DEFAULT_ENDPOINT),
+            experiment_exposures: reader.bool("amplitude_experiment_exposures", false)?,
+            experiment_flag_key_property: reader
+                .string_or("amplitude_experiment_flag_key_property", "flag_key"),
+            experiment_variant_property: reader
+                .string_or("amplitude_experiment_variant_property", "variant"),
+            experiment_key_property: reader
+                .string_or("amplitude_experiment_key_property", "experiment_key"),
+            session_replay: reader.bool("amplitude_session_replay", false)?,
+            autocapture_preset: reader.bool("amplitude_autocapture_preset", false)?,
+            session_events,
+            user_properties_merge: reader.choice(
+                "amplitude_user_properties_merge",
+                "both",
+                &[
+                    ("explicit", UserPropertiesMerge::Explicit),
+                    ("context", UserPropertiesMerge::Context),
+                    ("both", UserPropertiesMerge::Both),
+                ],
+            )?,
+            device_id_source: reader.list("amplitude_device_id_source"),
+            user_id_change: reader.choice(
+                "amplitude_user_id_change",
+                "identify",
+                &[
+                    ("identify", UserIdChange::Identify),
+                    ("usermap", UserIdChange::Usermap),
+                ],
+            )?,
+            usermap_endpoint: reader.string_or(
+                "amplitude_usermap_endpoint",
+                crate::DEFAULT_USERMAP_ENDPOINT,
+            ),
+            secret_key: reader.string("amplitude_secret_key"),
+            deletion_endpoint: reader.string_or(
+                "amplitude_deletion_endpoint",
+                crate::DEFAULT_DELETION_ENDPOINT,
+            ),
+            deletion_requester: reader.string("amplitude_deletion_requester"),
+            routes: reader.json("amplitude_routes", "a JSON array of routes")?,
+            internal_ips,
+            internal_user_ids,
+            internal_device_ids,
+            internal_traffic,
+            bot_filtering: reader.choice(
+                "amplitude_bot_filtering",
+                "disabled",
+                &[
+                    ("drop", Some(BotPolicy::Drop)),
+                    ("flag", Some(BotPolicy::Flag)),
+                    ("disabled", None),
+                ],
+            )?,
+            bot_patterns: reader.list("amplitude_bot_patterns"),
+            sample_rate,
+            sample_by_user_id: reader.choice(
+                "amplitude_sample_by",
+                "device_id",
+                &[("device_id", false), ("user_id", true)],
+            )?,
+            sample_rate_property: reader.bool("amplitude_sample_rate_property", false)?,
+            forward_client_headers: reader.bool("amplitude_forward_client_headers", true)?,
+            client_headers_allowlist: reader.list("amplitude_client_headers_allowlist"),
+            headers: reader.json(
+                "amplitude_headers",
+                "a JSON object of header names and values",
+            )?,
+            secret_headers: reader.json(
+                "amplitude_secret_headers",
+                "a JSON object of header names and values",
+            )?,
+            error_mode: reader.choice(
+                "amplitude_error_mode",
+                "lenient",
+                &[
+                    ("lenient", ErrorMode::Lenient),
+                    ("strict", ErrorMode::Strict),
+                ],
+            )?,
+            debug: reader.choice(
+                "amplitude_debug",
+                "disabled",
+                &[
+                    ("disabled", DebugOutput::Disabled),
+                    ("header", DebugOutput::Header),
+                    ("property", DebugOutput::Property),
+                ],
+            )?,
+            field_mappings: reader
+                .json("amplitude_field_mappings", "a JSON array of field mappings")?,
+            client_properties: reader.list("amplitude_client_properties"),
+            client_properties_destination: reader.choice(
+                "amplitude_client_properties_destination",
+                "event",
+                &[
+                    ("event", PropertyDestination::Event),
+                    ("user", PropertyDestination::User),
+                ],
+            )?,
+            static_event_properties: reader.json(
+                "amplitude_static_event_properties",
+                "a JSON object of property names and values",
+            )?,
+            static_user_properties: reader.json(
+                "amplitude_static_user_properties",
+                "a JSON object of property names and values",
+            )?,
+            static_properties_precedence: reader.choice(
+                "amplitude_static_properties_precedence",
+                "event",
+                &[
+                    ("event", StaticPrecedence::Event),
+                    ("static", StaticPrecedence::Static),
+                ],
+            )?,
+            unknown: vec![],
+        })
+    }
+}
+
+/// Values of `amplitude_session_events`.
+#[derive(Clone, Copy)]
+enum SessionEvents {
+    Names(&'static str, &'static str),
+    Custom,
+    Disabled,
+}
+
+/// Type of a setting, as declared in `edgee-component.toml`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    String,
+    Bool,
+    Number,
+    List,
+    Json,
+}
+
+/// Takes the settings out of the dictionary, remembering which were read.
+struct Reader {
+    values: HashMap<String, String>,
+    read: Vec<(&'static str, Kind)>,
+}
+
+impl Reader {
+    fn new(settings: &Dict) -> Self {
+        Self {
+            values: settings.iter().cloned().collect(),
+            read: vec![],
+        }
+    }
+
+    /// Trimmed value of the setting, `None` when it is missing or empty.
+    fn take(&mut self, key: &'static str, kind: Kind) -> Option<String> {
+        self.read.push((key, kind));
+        self.values
+            .remove(key)
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    }
+
+    fn string(&mut self, key: &'static str) -> Option<String> {
+        self.take(key, Kind::String)
+    }
+
+    fn string_or(&mut self, key: &'static str, default: &str) -> String {
+        self.string(key).unwrap_or_else(|| default.to_string())
+    }
+
+    fn bool(&mut self, key: &'static str, default: bool) -> anyhow::Result<bool> {
+        match self.take(key, Kind::Bool) {
+            None => Ok(default),
+            Some(value) if value.eq_ignore_ascii_case("true") => Ok(true),
+            Some(value) if value.eq_ignore_ascii_case("false") => Ok(false),
+            Some(other) => Err(anyhow!("Invalid {key} {other:?}, expected true or false")),
+        }
+    }
+
+    fn number(&mut self, key: &'static str) -> anyhow::Result<Option<f64>> {
+        self.take(key, Kind::Number)
+            .map(|value| {
+                value
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|number| number.is_finite())
+                    .ok_or_else(|| anyhow!("Invalid {key} {value:?}, expected a number"))
+            })
+            .transpose()
+    }
+
+    /// Comma-separated list, without the empty items.
+    fn list(&mut self, key: &'static str) -> Vec<String> {
+        self.take(key, Kind::List)
+            .map(|value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// One of a set of values, such as a policy.
+    fn choice<T: Clone>(
+        &mut self,
+        key: &'static str,
+        default: &str,
+        choices: &[(&str, T)],
+    ) -> anyhow::Result<T> {
+        let value = self.string_or(key, default);
+        if let Some((_, choice)) = choices.iter().find(|(name, _)| *name == value) {
+            return Ok(choice.clone());
+        }
+        let names: Vec<&str> = choices.iter().map(|(name, _)| *name).collect();
+        let expected = match names.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, rest)) => format!("{} or {last}", rest.join(", ")),
+            None => String::new(),
+        };
+        Err(anyhow!("Invalid {key} {value:?}, expected {expected}"))
+    }
+
+    fn json<T: DeserializeOwned + Default>(
+        &mut self,
+        key: &'static str,
+        expected: &str,
+    ) -> anyhow::Result<T> {
+        match self.take(key, Kind::Json) {
+            Some(value) => serde_json::from_str(&value)
+                .map_err(|e| anyhow!("Invalid {key}, expected {expected}: {e}")),
+            None => Ok(T::default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(values: &[(&str, &str)]) -> Dict {
+        values
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn parse(values: &[(&str, &str)]) -> anyhow::Result<Settings> {
+        let mut values = values.to_vec();
+        values.push(("amplitude_api_key", "abc"));
+        Settings::parse(&settings(&values))
+    }
+
+    #[test]
+    fn parses_typed_values_and_defaults() {
+        let parsed = parse(&[
+            ("amplitude_session_replay", " TRUE "),
+            ("amplitude_sample_rate", "0.25"),
+            ("amplitude_internal_user_ids", "qa-1, ,qa-2"),
+            ("amplitude_headers", r#"{"x-proxy": "1"}"#),
+            ("amplitude_endpoint", ""),
+        ])
+        .unwrap();
+
+        assert_eq!(parsed.api_key, "abc");
+        assert!(parsed.session_replay);
+        assert_eq!(parsed.sample_rate, Some(0.25));
+        assert_eq!(parsed.internal_user_ids, vec!["qa-1", "qa-2"]);
+        assert_eq!(parsed.headers.get("x-proxy").map(String::as_str), Some("1"));
+        assert_eq!(parsed.endpoint, crate::DEFAULT_ENDPOINT);
+        assert!(parsed.forward_client_headers);
+        assert_eq!(
+            parsed.session_events.map(|names| names.start).as_deref(),
+            Some("session_start")
+        );
+        assert_eq!(parsed.error_mode, ErrorMode::Lenient);
+        assert_eq!(parsed.bot_filtering, None);
+        assert!(parsed.routes.is_empty());
+    }
+
+    #[test]
+    fn errors_name_the_setting() {
+        for (key, value) in [
+            ("amplitude_session_replay", "yes"),
+            ("amplitude_sample_rate", "half"),
+            ("amplitude_sample_rate", "inf"),
+            ("amplitude_routes", "{}"),
+            ("amplitude_secret_headers", "x-proxy: 1"),
+        ] {
+            let error = parse(&[(key, value)]).unwrap_err().to_string();
+            assert!(error.contains(key), "{error}");
+        }
+
+        let error = Settings::parse(&settings(&[])).unwrap_err().to_string();
+        assert_eq!(error, "Missing amplitude_api_key");
+    }
+
+    #[test]
+    fn parses_choices() {
+        let parsed = parse(&[
+            ("amplitude_session_events", "custom"),
+            ("amplitude_session_start_event_name", "Visit Started"),
+            ("amplitude_session_end_event_name", "Visit Ended"),
+            ("amplitude_bot_filtering", "drop"),
+            ("amplitude_sample_by", "user_id"),
+            ("amplitude_internal_ips", "10.0.0.0/8"),
+            ("amplitude_internal_traffic", "sandbox"),
+            ("amplitude_internal_api_key", "qa-key"),
+            ("amplitude_static_properties_precedence", "static"),
+        ])
+        .unwrap();
+        assert_eq!(
+            parsed.session_events.map(|names| names.end).as_deref(),
+            Some("Visit Ended")
+        );
+        assert_eq!(parsed.bot_filtering, Some(BotPolicy::Drop));
+        assert!(parsed.sample_by_user_id);
+        assert_eq!(
+            parsed.internal_traffic,
+            InternalTrafficPolicy::Sandbox("qa-key".to_string())
+        );
+        assert_eq!(
+            parsed.static_properties_precedence,
+            StaticPrecedence::Static
+        );
+
+        let error = parse(&[("amplitude_bot_filtering", "block")])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            r#"Invalid amplitude_bot_filtering "block", expected drop, flag or disabled"#
+        );
+        for (key, value) in [
+            ("amplitude_session_events", "always"),
+            ("amplitude_user_id_change", "alias"),
+            ("amplitude_debug", "verbose"),
+            ("amplitude_client_properties_destination", "device"),
+            ("amplitude_static_properties_precedence", "first"),
+            ("amplitude_sample_rate", "0"),
+        ] {
+            let error = parse(&[(key, value)]).unwrap_err().to_string();
+            assert!(error.starts_with(&format!("Invalid {key}")), "{error}");
+        }
+    }
+
+    #[test]
+    fn sandbox_policy_requires_api_key() {
+        let error = parse(&[
+            ("amplitude_internal_ips", "10.0.0.0/8"),
+            ("amplitude_internal_traffic", "sandbox"),
+        ])
+        .unwrap_err()
+        .to_string();
+        assert!(error.starts_with("Missing amplitude_internal_api_key"));
+        assert!(parse(&[("amplitude_internal_traffic", "sandbox")]).is_ok());
+    }
+
+    #[test]
+    fn reports_unknown_settings() {
+        let parsed = parse(&[
+            ("amplitude_sesion_replay", "true"),
+            ("api_key", "abc"),
+            ("edgee_anonymization", "true"),
+        ])
+        .unwrap();
+        assert_eq!(parsed.unknown, vec!["amplitude_sesion_replay", "api_key"]);
+
+        let error = parse(&[
+            ("amplitude_sesion_replay", "true"),
+            ("api_key", "abc"),
+            ("amplitude_error_mode", "strict"),
+        ])
+        .unwrap_err()
+        .to_string();
+        assert_eq!(
+            error,
+            r#"Unknown setting "amplitude_sesion_replay", "api_key""#
+        );
+        assert!(parse(&[
+            ("edgee_anonymization", "true"),
+            ("amplitude_error_mode", "strict")
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn manifest_declares_every_setting() {
+        let mut reader = Reader::new(&settings(&[("amplitude_api_key", "abc")]));
+        Settings::read(&mut reader).unwrap();
+        let mut expected: Vec<(String, &str)> = reader
+            .read
+            .iter()
+            .map(|(key, kind)| {
+                let kind = if *kind == Kind::Bool {
+                    "bool"
+                } else {
+                    "string"
+                };
+                (key.to_string(), kind)
+            })
+            .collect();
+        expected.sort();
+
+        let mut declared = vec![];
+        let mut name = None;
+        for line in include_str!("../edgee-component.toml").lines() {
+            if let Some(setting) = line
+                .strip_prefix("[component.settings.")
+                .and_then(|line| line.strip_suffix(']'))
+            {
+                name = Some(setting.to_string());
+            } else if let Some(kind) = line.strip_prefix("type = ") {
+                if let Some(name) = name.take() {
+                    declared.push((name, kind.trim_matches('"')));
+                }
+            }
+        }
+        declared.sort();
+
+        assert_eq!(declared, expected);
+    }
+}
@@ -0,0 +1,98 @@
+//! Golden-file tests for the requests sent to Amplitude.
+//!
+//! Every directory under `tests/fixtures` is one case and contains:
+//! - `event.json`: the Edgee event given to the component
+//! - `settings.json`: the component settings, as a flat JSON object
+//! - `expected.json`: the resulting Edgee request, with the body decoded as JSON
+//!
+//! Fields missing from `event.json` take their default value, so a case only
+//! lists the fields it exercises.
+//!
+//! Run `UPDATE_SNAPSHOTS=1 cargo test` to rewrite the `expected.json` files
+//! from the current output.
+//!
+//! The native output of every case is also written to `target/native-requests`,
+//! where the wasm harness in `tests/wasm` compares the compiled component to it.
+
+use crate::exports::edgee::components::data_collection::{Data, Dict, EdgeeRequest, Event, Guest};
+use crate::fixtures::{self, load_case, read_json, request_to_json};
+use crate::AmplitudeComponent;
+use pretty_assertions::assert_eq;
+use serde_json::Value;
+use std::fs;
+use std::path::PathBuf;
+
+const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+const NATIVE_REQUESTS_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/target/native-requests");
+const UPDATE_ENV_VAR: &str = "UPDATE_SNAPSHOTS";
+
+/// Calls the component export matching the event data.
+pub(crate) fn call_component(event: Event, settings: Dict) -> Result<EdgeeRequest, String> {
+    match event.data {
+        Data::Page(_) => AmplitudeComponent::page(event, settings),
+        Data::Track(_) => AmplitudeComponent::track(event, settings),
+        Data::User(_) => AmplitudeComponent::user(event, settings),
+    }
+}
+
+pub(crate) fn fixture_dirs() -> Vec<PathBuf> {
+    fixtures::fixture_dirs(FIXTURES_DIR)
+}
+
+#[test]
+fn payload_snapshots() {
+    let update = std::env::var_os(UPDATE_ENV_VAR).is_some();
+    let dirs = fixture_dirs();
+    assert!(!dirs.is_empty(), "no fixtures found in {FIXTURES_DIR}");
+    fs::create_dir_all(NATIVE_REQUESTS_DIR).unwrap();
+
+    for dir in dirs {
+        let (name, event, settings) = load_case(&dir);
+        let actual = request_to_json(call_component(event, settings));
+        let expected_path = dir.join("expected.json");
+        let content = serde_json::to_string_pretty(&actual).unwrap() + "\n";
+        fs::write(format!("{NATIVE_REQUESTS_DIR}/{name}.json"), &content).unwrap();
+
+        if update {
+            fs::write(&expected_path, content).unwrap();
+            continue;
+        }
+
+        assert!(
+            expected_path.exists(),
+            "{name}: missing expected.json, run with {UPDATE_ENV_VAR}=1 to create it"
+        );
+        let expected = read_json(&expected_path);
+        assert_eq!(
+            expected, actual,
+            "snapshot mismatch for {name}, run with {UPDATE_ENV_VAR}=1 to update"
+        );
+    }
+}
+
+/// User properties are sent through operations only, see
+/// `AmplitudeEvent::set_user_property`.
+#[test]
+fn user_properties_never_mix_operations() {
+    for dir in fixture_dirs() {
+        let (name, event, settings) = load_case(&dir);
+        let Ok(request) = call_component(event, settings) else {
+            continue;
+        };
+        let Ok(body) = serde_json::from_str::<Value>(&request.body) else {
+            continue;
+        };
+        let events = body["events"].as_array().cloned().unwrap_or_default();
+        for event in events {
+            let Some(properties) = event["user_properties"].as_object() else {
+                continue;
+            };
+            let operations = properties.keys().filter(|key| key.starts_with('$')).count();
+            assert!(
+                operations == 0 || operations == properties.len(),
+                "{name}: {} mixes operations and plain user properties",
+                event["event_type"]
+            );
+        }
+    }
+}
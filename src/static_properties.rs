@@ -0,0 +1,71 @@
+//! Constant event and user properties added to every event, such as
+//! `environment` or `site`, so frontends don't have to send them.
+
+use serde_json::{Map, Value};
+
+use crate::amplitude_payload::AmplitudeEvent;
+use crate::trace::Trace;
+
+/// Which value is kept when the event already has a static property.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) enum StaticPrecedence {
+    /// The value from the event wins, static properties only fill the gaps.
+    #[default]
+    Event,
+    /// The static value overrides the value from the event.
+    Static,
+}
+
+/// Static event properties, and user properties sent with `$set`.
+#[derive(Debug)]
+pub(crate) struct StaticProperties {
+    event_properties: Map<String, Value>,
+    user_properties: Map<String, Value>,
+    precedence: StaticPrecedence,
+}
+
+impl StaticProperties {
+    /// Returns `None` when no static property is configured.
+    pub(crate) fn new(
+        event_properties: Map<String, Value>,
+        user_properties: Map<String, Value>,
+        precedence: StaticPrecedence,
+    ) -> Option<Self> {
+        if event_properties.is_empty() && user_properties.is_empty() {
+            return None;
+        }
+        Some(Self {
+            event_properties,
+            user_properties,
+            precedence,
+        })
+    }
+
+    pub(crate) fn apply(&self, event: &mut AmplitudeEvent, trace: &mut Trace) {
+        let overrides = self.precedence == StaticPrecedence::Static;
+        for (key, value) in &self.event_properties {
+            let field = format!("event_properties.{key}");
+            if overrides || event.event_property(key).is_none() {
+                event.insert_event_property(key, value.clone());
+                trace.source(&field, "amplitude_static_event_properties");
+            } else {
+                trace.skip(
+                    &format!("amplitude_static_event_properties.{key}"),
+                    "overridden by the event",
+                );
+            }
+        }
+        for (key, value) in &self.user_properties {
+            let field = format!("user_properties.$set.{key}");
+            if overrides || event.user_property("$set", key).is_none() {
+                event.set_user_property(key, value.clone());
+                trace.source(&field, "amplitude_static_user_properties");
+            } else {
+                trace.skip(
+                    &format!("amplitude_static_user_properties.{key}"),
+                    "overridden by the event",
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,207 @@
+//! Trace of the mapping decisions, attached to the requests sent to the HTTP
+//! V2 API when the `amplitude_debug` setting is enabled.
+//!
+//! It tells why a field is missing in Amplitude: Edgee didn't provide it (it
+//! is not in `fields`), the component skipped it (it is in `skipped`), or
+//! Amplitude dropped it (it is in `fields` but not in Amplitude).
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Header holding the trace in `header` mode.
+pub(crate) const TRACE_HEADER: &str = "x-edgee-amplitude-trace";
+/// Event property holding the trace in `property` mode.
+pub(crate) const TRACE_PROPERTY: &str = "$edgee_trace";
+/// Longest trace sent in the header, well below the header size limits of
+/// common proxies (8 KB).
+pub(crate) const MAX_HEADER_LENGTH: usize = 4096;
+
+/// Where the trace is attached.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) enum DebugOutput {
+    /// No trace, the default.
+    #[default]
+    Disabled,
+    /// In the `x-edgee-amplitude-trace` request header.
+    Header,
+    /// In the `$edgee_trace` property of the event.
+    Property,
+}
+
+/// A field of the Edgee event left out of the request.
+#[derive(Serialize, Debug, PartialEq)]
+struct Skipped {
+    field: String,
+    reason: String,
+}
+
+/// Decisions recorded while building a request. Recording is a no-op when
+/// debugging is disabled.
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct Trace {
+    #[serde(skip)]
+    pub(crate) output: DebugOutput,
+    /// Edgee field each Amplitude field is read from.
+    fields: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    skipped: Vec<Skipped>,
+    /// Filters applied to the event: bots, internal traffic, sampling.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    filters: Vec<String>,
+    /// Events built by the component, not mapped from the Edgee event.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    synthesized: Vec<String>,
+    /// Settings the component doesn't know, ignored outside of strict mode.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    unknown_settings: Vec<String>,
+}
+
+impl Trace {
+    pub(crate) fn new(output: DebugOutput) -> Self {
+        Self {
+            output,
+            ..Self::default()
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.output != DebugOutput::Disabled
+    }
+
+    pub(crate) fn source(&mut self, field: &str, source: &str) {
+        if self.enabled() {
+            self.fields.insert(field.to_string(), source.to_string());
+        }
+    }
+
+    /// Records the source of a field unless one is already recorded, so that
+    /// the context doesn't replace the source of a field the event data set.
+    pub(crate) fn fallback_source(&mut self, field: &str, source: &str) {
+        if self.enabled() {
+            self.fields
+                .entry(field.to_string())
+                .or_insert_with(|| source.to_string());
+        }
+    }
+
+    pub(crate) fn skip(&mut self, field: &str, reason: &str) {
+        if self.enabled() {
+            self.skipped.push(Skipped {
+                field: field.to_string(),
+                reason: reason.to_string(),
+            });
+        }
+    }
+
+    pub(crate) fn filter(&mut self, decision: &str) {
+        if self.enabled() {
+            self.filters.push(decision.to_string());
+        }
+    }
+
+    pub(crate) fn synthesize(&mut self, event_type: &str) {
+        if self.enabled() {
+            self.synthesized.push(event_type.to_string());
+        }
+    }
+
+    pub(crate) fn unknown_setting(&mut self, key: &str) {
+        if self.enabled() {
+            self.unknown_settings.push(key.to_string());
+        }
+    }
+
+    pub(crate) fn to_value(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap_or_default()
+    }
+
+    /// The trace as a header value: JSON with the non-ASCII characters
+    /// escaped, or `None` when it is longer than `MAX_HEADER_LENGTH`.
+    pub(crate) fn to_header(&self) -> Option<String> {
+        let json = self.to_value().to_string();
+        let mut header = String::with_capacity(json.len());
+        for c in json.chars() {
+            if c.is_ascii() {
+                header.push(c);
+            } else {
+                let mut units = [0; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    header.push_str(&format!("\\u{unit:04x}"));
+                }
+            }
+        }
+        (header.len() <= MAX_HEADER_LENGTH).then_some(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_nothing_when_disabled() {
+        let mut trace = Trace::default();
+        trace.source("user_id", "context.user.user_id");
+        trace.skip("session_id", "invalid");
+        trace.filter("bot: flagged");
+        trace.synthesize("session_start");
+        trace.unknown_setting("amplitude_sesion_replay");
+        assert_eq!(trace.to_value(), serde_json::json!({"fields": {}}));
+    }
+
+    #[test]
+    fn records_decisions() {
+        let mut trace = Trace::new(DebugOutput::Header);
+        trace.source("user_id", "context.user.user_id");
+        trace.skip("context.session.session_id", "invalid value");
+        trace.synthesize("session_start");
+        assert_eq!(
+            trace.to_value(),
+            serde_json::json!({
+                "fields": {"user_id": "context.user.user_id"},
+                "skipped": [{"field": "context.session.session_id", "reason": "invalid value"}],
+                "synthesized": ["session_start"],
+            })
+        );
+    }
+
+    #[test]
+    fn fallback_source_keeps_the_recorded_source() {
+        let mut trace = Trace::new(DebugOutput::Header);
+        trace.source("user_id", "data.user_id");
+        trace.fallback_source("user_id", "context.user.user_id");
+        trace.fallback_source("ip", "context.client.ip");
+        assert_eq!(
+            trace.to_value(),
+            serde_json::json!({
+                "fields": {"user_id": "data.user_id", "ip": "context.client.ip"},
+            })
+        );
+    }
+
+    #[test]
+    fn header_is_ascii() {
+        let mut trace = Trace::new(DebugOutput::Header);
+        trace.source("event_properties.prénom", "data.properties.prénom");
+        trace.source("event_properties.🎉", "data.properties.🎉");
+        let header = trace.to_header().unwrap();
+        assert!(header.is_ascii());
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&header).unwrap(),
+            trace.to_value()
+        );
+    }
+
+    #[test]
+    fn header_is_capped() {
+        let mut trace = Trace::new(DebugOutput::Header);
+        for index in 0..200 {
+            trace.source(
+                &format!("event_properties.property_{index}"),
+                &format!("data.properties.property_{index}"),
+            );
+        }
+        assert!(trace.to_value().to_string().len() > MAX_HEADER_LENGTH);
+        assert_eq!(trace.to_header(), None);
+    }
+}
@@ -0,0 +1,144 @@
+//! Runs the compiled `wasm32-wasip2` component in wasmtime against the
+//! golden files in `tests/fixtures`.
+//!
+//! The host is a stub: WASI without environment, filesystem or network. Each
+//! fixture is loaded with the same code as the native snapshot tests, turned
+//! into real WIT values and given to the matching `data-collection` export.
+//! The request it returns must be identical to the native output of the
+//! snapshot tests, and to `expected.json`. Run the native tests and build the
+//! component first, or run `make test.wasm`.
+
+use pretty_assertions::assert_eq;
+use std::path::Path;
+use wasmtime::component::{Component, Linker, ResourceTable};
+use wasmtime::{Engine, Store};
+use wasmtime_wasi::p2::{IoView, WasiCtx, WasiCtxBuilder, WasiView};
+
+use exports::edgee::components::data_collection::{Data, Dict, EdgeeRequest, Event, UserData};
+use fixtures::{fixture_dirs, load_case, read_json, request_to_json};
+
+#[path = "../../src/fixtures.rs"]
+mod fixtures;
+
+wasmtime::component::bindgen!({
+    world: "data-collection",
+    path: "../../.edgee/wit",
+});
+
+const FIXTURES_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../fixtures");
+const DEFAULT_WASM_PATH: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../../target/wasm32-wasip2/release/amplitude_component.wasm"
+);
+const NATIVE_REQUESTS_DIR: &str =
+    concat!(env!("CARGO_MANIFEST_DIR"), "/../../target/native-requests");
+const WASM_PATH_ENV_VAR: &str = "AMPLITUDE_COMPONENT_WASM";
+
+struct StubHost {
+    ctx: WasiCtx,
+    table: ResourceTable,
+}
+
+impl IoView for StubHost {
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+}
+
+impl WasiView for StubHost {
+    fn ctx(&mut self) -> &mut WasiCtx {
+        &mut self.ctx
+    }
+}
+
+struct Harness {
+    store: Store<StubHost>,
+    bindings: DataCollection,
+}
+
+impl Harness {
+    fn new() -> Self {
+        let wasm_path =
+            std::env::var(WASM_PATH_ENV_VAR).unwrap_or_else(|_| DEFAULT_WASM_PATH.to_string());
+        assert!(
+            Path::new(&wasm_path).exists(),
+            "{wasm_path} not found, build it with `cargo build --target wasm32-wasip2 --release`"
+        );
+
+        let engine = Engine::default();
+        let component = Component::from_file(&engine, &wasm_path).unwrap();
+        let mut linker = Linker::new(&engine);
+        wasmtime_wasi::p2::add_to_linker_sync(&mut linker).unwrap();
+
+        let mut store = Store::new(
+            &engine,
+            StubHost {
+                ctx: WasiCtxBuilder::new().build(),
+                table: ResourceTable::new(),
+            },
+        );
+        let bindings = DataCollection::instantiate(&mut store, &component, &linker).unwrap();
+
+        Self { store, bindings }
+    }
+
+    fn call(&mut self, event: &Event, settings: &Dict) -> Result<EdgeeRequest, String> {
+        let guest = self.bindings.edgee_components_data_collection();
+        match event.data {
+            Data::Page(_) => guest.call_page(&mut self.store, event, settings),
+            Data::Track(_) => guest.call_track(&mut self.store, event, settings),
+            Data::User(_) => guest.call_user(&mut self.store, event, settings),
+        }
+        .expect("the component trapped")
+    }
+}
+
+#[test]
+fn component_matches_native_snapshots() {
+    let mut harness = Harness::new();
+    let dirs = fixture_dirs(FIXTURES_DIR);
+    assert!(!dirs.is_empty(), "no fixtures found in {FIXTURES_DIR}");
+
+    for dir in dirs {
+        let (name, event, settings) = load_case(&dir);
+        let actual = request_to_json(harness.call(&event, &settings));
+
+        let native_path = Path::new(NATIVE_REQUESTS_DIR).join(format!("{name}.json"));
+        assert!(
+            native_path.exists(),
+            "{name}: no native output in {NATIVE_REQUESTS_DIR}, run `cargo test --lib snapshots`"
+        );
+        assert_eq!(
+            read_json(&native_path),
+            actual,
+            "wasm output differs from the native output for {name}"
+        );
+        assert_eq!(
+            read_json(&dir.join("expected.json")),
+            actual,
+            "wasm output differs from the native snapshot for {name}"
+        );
+    }
+}
+
+#[test]
+fn component_reports_missing_data() {
+    let mut harness = Harness::new();
+    let dir = fixture_dirs(FIXTURES_DIR)
+        .into_iter()
+        .find(|dir| dir.ends_with("page_consent_granted"))
+        .unwrap();
+    let (_, mut event, _) = load_case(&dir);
+    event.data = Data::User(UserData {
+        user_id: String::new(),
+        anonymous_id: String::new(),
+        edgee_id: String::new(),
+        properties: vec![],
+    });
+
+    let guest = harness.bindings.edgee_components_data_collection();
+    let result = guest
+        .call_page(&mut harness.store, &event, &vec![])
+        .expect("the component trapped");
+    assert!(result.is_err());
+}